@@ -0,0 +1,386 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A local cache of the certificates sevctl downloads from the AMD Key
+//! Distribution Service, so that hosts without network access can be
+//! pre-seeded from a connected machine.
+
+use crate::config::{Config, Options};
+use crate::error::{Context, Contextual, Result};
+use crate::firmware::Platform;
+use crate::output::Output;
+use crate::probe::Host;
+use crate::vcek::{self, Product};
+
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use ::sev::certs::sev;
+use codicon::Decoder;
+use openssl::x509::X509;
+use serde::Serialize;
+use serde_json::json;
+use structopt::StructOpt;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Size of a chip identifier in bytes, as the firmware reports it.
+const CHIP_ID_LEN: usize = 64;
+
+#[derive(StructOpt)]
+pub enum CacheCmd {
+    #[structopt(about = "Add a certificate downloaded on another machine to the cache")]
+    Import {
+        #[structopt(subcommand)]
+        cmd: ImportCmd,
+    },
+
+    #[structopt(about = "List the cached certificates")]
+    List,
+
+    #[structopt(about = "Remove cached certificates")]
+    Prune {
+        #[structopt(long, help = "Only remove entries older than this many days")]
+        older_than: Option<u64>,
+    },
+}
+
+#[derive(StructOpt)]
+pub enum ImportCmd {
+    #[structopt(about = "Import a SEV CEK certificate")]
+    Cek {
+        #[structopt(
+            long,
+            help = "Chip identifier the CEK belongs to (128 hex digits); read from the firmware if omitted"
+        )]
+        id: Option<String>,
+
+        #[structopt(parse(from_os_str), help = "CEK certificate file path")]
+        file: PathBuf,
+    },
+
+    #[structopt(about = "Import a SEV-SNP ASK/ARK certificate chain")]
+    Chain {
+        #[structopt(
+            long,
+            help = "Processor product name (Milan, Genoa); detected from CPUID if omitted"
+        )]
+        product: Option<Product>,
+
+        #[structopt(parse(from_os_str), help = "ASK/ARK chain (PEM) file path")]
+        file: PathBuf,
+    },
+
+    #[structopt(about = "Import a SEV-SNP VCEK certificate")]
+    Vcek {
+        #[structopt(
+            long,
+            help = "Processor product name (Milan, Genoa); detected from CPUID if omitted"
+        )]
+        product: Option<Product>,
+
+        #[structopt(
+            long,
+            help = "Chip identifier the VCEK belongs to (128 hex digits); read from the firmware if omitted"
+        )]
+        id: Option<String>,
+
+        #[structopt(parse(from_os_str), help = "VCEK certificate (DER or PEM) file path")]
+        file: PathBuf,
+    },
+}
+
+/// Where downloaded certificates are kept, and where and whether they may
+/// be downloaded from.
+pub struct Cache {
+    dir: Option<PathBuf>,
+    offline: bool,
+    options: Options,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    age_days: u64,
+}
+
+impl Cache {
+    /// Use `dir`, or the default cache directory if `None`.
+    pub fn new(dir: Option<PathBuf>, offline: bool, options: Options) -> Self {
+        Self {
+            dir: dir.or_else(default_dir),
+            offline,
+            options,
+        }
+    }
+
+    /// Return the cached contents of `key`, or download them from `path` on
+    /// the KDS and store them under `key`, as decoded by `parse`.
+    ///
+    /// Only contents that `parse` accepts are stored; a cached entry that it
+    /// rejects is removed and downloaded again.
+    pub fn fetch<T, F>(&self, key: &Path, path: &str, parse: F) -> Result<T>
+    where
+        F: Fn(&[u8]) -> Result<T>,
+    {
+        if let Some(parsed) = self.cached(key, &parse) {
+            return Ok(parsed);
+        }
+
+        if self.offline {
+            return Err(Context::new(
+                &format!("{} is not cached", path),
+                Box::new(crate::error::Error::Offline),
+            ));
+        }
+
+        let config = Config::load(&self.options)?;
+        let url = format!("{}{}", config.kds_url, path);
+        let mut contents = vec![];
+        crate::fetch(&url, &roots(&config)?, &config.retry)?
+            .into_reader()
+            .read_to_end(&mut contents)
+            .context(format!("unable to read {}", url))?;
+        let parsed = parse(&contents)?;
+
+        // The cache only saves downloads, so failing to update it is not fatal.
+        let _ = self.store(key, &contents);
+
+        Ok(parsed)
+    }
+
+    /// The cached contents of `key`, as decoded by `parse`, without
+    /// downloading them. An entry that `parse` rejects is removed.
+    pub fn cached<T, F>(&self, key: &Path, parse: F) -> Option<T>
+    where
+        F: Fn(&[u8]) -> Result<T>,
+    {
+        let path = self.path(key)?;
+        let contents = fs::read(&path).ok()?;
+
+        match parse(&contents) {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    fn path(&self, key: &Path) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(key))
+    }
+
+    fn dir(&self) -> Result<&Path> {
+        self.dir.as_deref().ok_or_else(|| {
+            Context::new(
+                "no cache directory; set --cache-dir",
+                Box::<Error>::new(ErrorKind::NotFound.into()),
+            )
+        })
+    }
+
+    fn store(&self, key: &Path, contents: &[u8]) -> Result<PathBuf> {
+        let path = self.dir()?.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(format!("unable to create {}", parent.display()))?;
+        }
+        fs::write(&path, contents).context(format!("unable to write {}", path.display()))?;
+
+        Ok(path)
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>> {
+        let dir = self.dir()?;
+        let mut entries = vec![];
+        if dir.exists() {
+            walk(dir, dir, &mut entries)?;
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(entries)
+    }
+}
+
+/// The extra trust anchors from every certificate in the CA files.
+fn roots(config: &Config) -> Result<Vec<native_tls::Certificate>> {
+    let mut roots = vec![];
+    for file in &config.ca_files {
+        let pem = fs::read(file).context(format!("unable to read {}", file.display()))?;
+        let certs = X509::stack_from_pem(&pem)
+            .context(format!("invalid CA certificates in {}", file.display()))?;
+        for cert in certs {
+            let der = cert.to_der().context("unable to encode CA certificate")?;
+            roots.push(
+                native_tls::Certificate::from_der(&der)
+                    .context(format!("invalid CA certificates in {}", file.display()))?,
+            );
+        }
+    }
+
+    Ok(roots)
+}
+
+/// `/var/cache/sevctl` for root, `$XDG_CACHE_HOME/sevctl` or
+/// `~/.cache/sevctl` for everyone else.
+fn default_dir() -> Option<PathBuf> {
+    if unsafe { libc::geteuid() } == 0 {
+        return Some(PathBuf::from("/var/cache/sevctl"));
+    }
+
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))
+        .map(|d| d.join("sevctl"))
+}
+
+fn walk(root: &Path, dir: &Path, entries: &mut Vec<Entry>) -> Result<()> {
+    let listing = fs::read_dir(dir).context(format!("unable to list {}", dir.display()))?;
+
+    for item in listing {
+        let item = item.context(format!("unable to list {}", dir.display()))?;
+        let meta = item
+            .metadata()
+            .context(format!("unable to stat {}", item.path().display()))?;
+
+        if meta.is_dir() {
+            walk(root, &item.path(), entries)?;
+            continue;
+        }
+
+        let age = meta
+            .modified()
+            .ok()
+            .and_then(|m| SystemTime::now().duration_since(m).ok())
+            .unwrap_or_default();
+        entries.push(Entry {
+            path: item.path().strip_prefix(root).unwrap().to_path_buf(),
+            size: meta.len(),
+            age_days: age.as_secs() / DAY.as_secs(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Check that `id` is a hex-encoded chip identifier, so that it can only
+/// name a single file in the cache.
+fn chip_id(id: &str) -> Result<&str> {
+    if id.len() != CHIP_ID_LEN * 2 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Context::new(
+            &format!(
+                "chip identifier must be {} hex digits, found {:?}",
+                CHIP_ID_LEN * 2,
+                id
+            ),
+            Box::<Error>::new(ErrorKind::InvalidInput.into()),
+        ));
+    }
+
+    Ok(id)
+}
+
+/// Cache key of the CEK of a chip.
+pub fn cek_key(id: &str) -> Result<PathBuf> {
+    let id = chip_id(id)?;
+    Ok(Path::new("cek").join(format!("{}.cert", id.to_uppercase())))
+}
+
+/// Cache key of the SEV-SNP ASK/ARK chain of a product line.
+pub fn ca_chain_key(product: Product) -> PathBuf {
+    Path::new("snp")
+        .join(product.to_string())
+        .join("cert_chain.pem")
+}
+
+/// Cache key of the VCEK of a chip at a TCB version.
+pub fn vcek_key(product: Product, hwid: &str, tcb: &crate::snp::TcbVersion) -> Result<PathBuf> {
+    let hwid = chip_id(hwid)?;
+    Ok(Path::new("snp").join(product.to_string()).join(format!(
+        "{}_{:02}_{:02}_{:02}_{:02}.der",
+        hwid.to_lowercase(),
+        tcb.bootloader,
+        tcb.tee,
+        tcb.snp,
+        tcb.microcode
+    )))
+}
+
+pub fn cmd(cmd: CacheCmd, cache: &Cache, platform: &Platform, output: Output) -> Result<()> {
+    match cmd {
+        CacheCmd::List => {
+            let entries = cache.entries()?;
+            match output {
+                Output::Text => {
+                    for e in &entries {
+                        println!(
+                            "{} ({} bytes, {} days old)",
+                            e.path.display(),
+                            e.size,
+                            e.age_days
+                        );
+                    }
+                }
+                Output::Json => output.json(&json!({ "entries": entries }))?,
+            }
+        }
+        CacheCmd::Import { cmd } => {
+            let path = import(cmd, cache, platform)?;
+            output.json(&json!({ "path": path }))?;
+        }
+        CacheCmd::Prune { older_than } => {
+            let dir = cache.dir()?;
+            let mut removed = vec![];
+            for e in cache.entries()? {
+                if older_than.map_or(true, |days| e.age_days >= days) {
+                    fs::remove_file(dir.join(&e.path))
+                        .context(format!("unable to remove {}", e.path.display()))?;
+                    removed.push(e.path);
+                }
+            }
+            output.json(&json!({ "removed": removed }))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn import(cmd: ImportCmd, cache: &Cache, platform: &Platform) -> Result<PathBuf> {
+    let read = |file: &Path| fs::read(file).context(format!("unable to read {}", file.display()));
+
+    match cmd {
+        ImportCmd::Cek { id, file } => {
+            let contents = read(&file)?;
+            sev::Certificate::decode(&mut &contents[..], ()).context("unable to decode CEK")?;
+
+            let id = match id {
+                Some(id) => id,
+                None => crate::chip_id(platform)?,
+            };
+            cache.store(&cek_key(&id)?, &contents)
+        }
+        ImportCmd::Chain { product, file } => {
+            let contents = read(&file)?;
+            vcek::ca_chain(&contents)?;
+
+            let product = vcek::product_or_detect(product, &Host(platform))?;
+            cache.store(&ca_chain_key(product), &contents)
+        }
+        ImportCmd::Vcek { product, id, file } => {
+            let cert = vcek::load_cert(&read(&file)?).context("unable to decode VCEK")?;
+            let tcb = vcek::tcb(&cert)?;
+            let der = cert.to_der().context("unable to encode VCEK")?;
+
+            let product = vcek::product_or_detect(product, &Host(platform))?;
+            let id = match id {
+                Some(id) => id,
+                None => crate::chip_id(platform)?,
+            };
+            cache.store(&vcek_key(product, &id, &tcb)?, &der)
+        }
+    }
+}
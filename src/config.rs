@@ -0,0 +1,225 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Settings for reaching the AMD Key Distribution Service (KDS), taken from
+//! the command line, the environment, or a configuration file, in that order
+//! of precedence.
+//!
+//! The configuration file holds one `key = value` setting per line; blank
+//! lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! kds-url = https://kds.example.com
+//! ca-file = /etc/pki/example-ca.pem
+//! retries = 5
+//! max-backoff = 120
+//! retry-jitter = 2
+//! ```
+
+use crate::error::{Context, Contextual, Result};
+
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const DEFAULT_KDS_URL: &str = "https://kdsintf.amd.com";
+
+const ENV_CONFIG: &str = "SEVCTL_CONFIG";
+const ENV_KDS_URL: &str = "SEVCTL_KDS_URL";
+const ENV_CA_FILE: &str = "SEVCTL_CA_FILE";
+const ENV_RETRIES: &str = "SEVCTL_RETRIES";
+const ENV_MAX_BACKOFF: &str = "SEVCTL_MAX_BACKOFF";
+const ENV_RETRY_JITTER: &str = "SEVCTL_RETRY_JITTER";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Base URL of the KDS, without a trailing slash.
+    pub kds_url: String,
+    /// PEM files of additional trust anchors for HTTPS connections.
+    pub ca_files: Vec<PathBuf>,
+    /// How to retry failed downloads.
+    pub retry: Retry,
+}
+
+/// Downloads that fail transiently are retried with exponential backoff.
+#[derive(Clone, Debug)]
+pub struct Retry {
+    /// Attempts made after the first one fails.
+    pub retries: u32,
+    /// Upper bound of the delay between attempts.
+    pub max_backoff: Duration,
+    /// Upper bound of the random delay added to each backoff.
+    pub jitter: Duration,
+}
+
+/// The settings given on the command line, to be combined with the
+/// environment and the configuration file only once a download needs them,
+/// so that a malformed setting does not fail commands that never download.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub config: Option<PathBuf>,
+    pub kds_url: Option<String>,
+    pub ca_files: Vec<PathBuf>,
+    pub retries: Option<u32>,
+    pub max_backoff: Option<u64>,
+    pub retry_jitter: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kds_url: DEFAULT_KDS_URL.to_string(),
+            ca_files: vec![],
+            retry: Retry::default(),
+        }
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            max_backoff: Duration::from_secs(60),
+            jitter: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Config {
+    /// Combine the command line options with the environment and the
+    /// configuration file they name (or the default location).
+    pub fn load(options: &Options) -> Result<Self> {
+        let path = options
+            .config
+            .clone()
+            .or_else(|| env::var_os(ENV_CONFIG).map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None if default_path().exists() => Self::from_file(&default_path())?,
+            None => Self::default(),
+        };
+
+        if let Some(url) = options
+            .kds_url
+            .clone()
+            .or_else(|| env::var(ENV_KDS_URL).ok())
+        {
+            config.kds_url = url;
+        }
+        config.kds_url = config.kds_url.trim_end_matches('/').to_string();
+
+        if !options.ca_files.is_empty() {
+            config.ca_files = options.ca_files.clone();
+        } else if let Some(files) = env::var_os(ENV_CA_FILE) {
+            config.ca_files = env::split_paths(&files).collect();
+        }
+
+        if let Some(retries) = or_env(options.retries, ENV_RETRIES)? {
+            config.retry.retries = retries;
+        }
+        if let Some(secs) = or_env(options.max_backoff, ENV_MAX_BACKOFF)? {
+            config.retry.max_backoff = Duration::from_secs(secs);
+        }
+        if let Some(secs) = or_env(options.retry_jitter, ENV_RETRY_JITTER)? {
+            config.retry.jitter = Duration::from_secs(secs);
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let mut config = Self::default();
+        for (n, key, value) in settings(path, "configuration file")? {
+            match &key[..] {
+                "kds-url" => config.kds_url = value,
+                "ca-file" => config.ca_files.push(PathBuf::from(value)),
+                "retries" => config.retry.retries = number(path, n, &key, &value)?,
+                "max-backoff" => {
+                    config.retry.max_backoff = Duration::from_secs(number(path, n, &key, &value)?)
+                }
+                "retry-jitter" => {
+                    config.retry.jitter = Duration::from_secs(number(path, n, &key, &value)?)
+                }
+                _ => return Err(invalid(path, n, &format!("unknown setting {}", key))),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Read the `key = value` settings of the file at `path`, along with the
+/// (zero-based) line each was found on. Blank lines and lines starting with
+/// `#` are skipped.
+pub fn settings(path: &Path, what: &str) -> Result<Vec<(usize, String, String)>> {
+    let contents =
+        fs::read_to_string(path).context(format!("unable to read {} {}", what, path.display()))?;
+
+    let mut settings = vec![];
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.find('=') {
+            Some(pos) => settings.push((
+                n,
+                line[..pos].trim().to_string(),
+                line[pos + 1..].trim().to_string(),
+            )),
+            None => return Err(invalid(path, n, "expected `key = value`")),
+        }
+    }
+
+    Ok(settings)
+}
+
+/// An error in the setting on (zero-based) `line` of `path`.
+pub fn invalid(path: &Path, line: usize, msg: &str) -> Context {
+    Context::new(
+        &format!("{}:{}: {}", path.display(), line + 1, msg),
+        Box::<Error>::new(ErrorKind::InvalidData.into()),
+    )
+}
+
+/// Parse `value`, the setting of `key` on `line` of `path`, as a number.
+pub fn number<T: FromStr>(path: &Path, line: usize, key: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(path, line, &format!("{} must be a number", key)))
+}
+
+/// `value`, or else the parsed environment variable `name` if it is set.
+fn or_env<T: FromStr>(value: Option<T>, name: &str) -> Result<Option<T>> {
+    if value.is_some() {
+        return Ok(value);
+    }
+
+    match env::var(name) {
+        Ok(value) => value.trim().parse().map(Some).map_err(|_| {
+            Context::new(
+                &format!("{} must be a number", name),
+                Box::<Error>::new(ErrorKind::InvalidInput.into()),
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/sevctl/config` or `~/.config/sevctl/config` if it
+/// exists, and `/etc/sevctl/config` otherwise.
+fn default_path() -> PathBuf {
+    let user = env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+        .map(|d| d.join("sevctl").join("config"));
+
+    match user {
+        Some(path) if path.exists() => path,
+        _ => PathBuf::from("/etc/sevctl/config"),
+    }
+}
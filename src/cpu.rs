@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Identification of the EPYC processor generation from the family and
+//! model reported by CPUID.
+
+use crate::error::{Context, Contextual, Result};
+use crate::probe::Probe;
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use serde::Serialize;
+
+/// The vendor identification string of AMD processors.
+pub const AMD: &str = "AuthenticAMD";
+
+/// The vendor identification string of the processor probed, from EBX, EDX
+/// and ECX of CPUID leaf 0.
+pub fn vendor(probe: &dyn Probe) -> String {
+    let leaf = probe.cpuid(0x0000_0000);
+    let bytes: Vec<u8> = [leaf.ebx, leaf.edx, leaf.ecx]
+        .iter()
+        .flat_map(|reg| reg.to_le_bytes().to_vec())
+        .collect();
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The family, model and stepping of a processor.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Signature {
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+}
+
+impl Signature {
+    pub fn read(probe: &dyn Probe) -> Self {
+        probe.cpuid(0x0000_0001).eax.into()
+    }
+}
+
+/// Decode the EAX register of CPUID leaf 1. The extended family and model
+/// only apply to family 0xf and above.
+impl From<u32> for Signature {
+    fn from(eax: u32) -> Self {
+        let mut family = (eax >> 8) & 0xf;
+        let mut model = (eax >> 4) & 0xf;
+        if family == 0xf {
+            family += (eax >> 20) & 0xff;
+            model |= (eax >> 12) & 0xf0;
+        }
+
+        Self {
+            family,
+            model,
+            stepping: eax & 0xf,
+        }
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "family {:#x} model {:#x} stepping {:#x}",
+            self.family, self.model, self.stepping
+        )
+    }
+}
+
+/// A generation of EPYC processors.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Generation {
+    Naples,
+    Rome,
+    Milan,
+    Genoa,
+    Bergamo,
+    Turin,
+}
+
+impl Generation {
+    /// The generation of the processor probed.
+    pub fn detect(probe: &dyn Probe) -> Result<Self> {
+        let vendor = vendor(probe);
+        let signature = Signature::read(probe);
+
+        Self::from_signature(&vendor, &signature).ok_or_else(|| {
+            let msg = match &vendor[..] {
+                AMD => format!("unknown AMD processor generation ({})", signature),
+                _ => format!("not an AMD processor ({:?}, {})", vendor, signature),
+            };
+            Context::new(&msg, Box::<Error>::new(ErrorKind::NotFound.into()))
+        })
+    }
+
+    /// The generation of a processor from `vendor` with `signature`; other
+    /// vendors' processors can share the family numbers of AMD's.
+    pub fn from_signature(vendor: &str, signature: &Signature) -> Option<Self> {
+        if vendor != AMD {
+            return None;
+        }
+
+        match (signature.family, signature.model) {
+            (0x17, 0x00..=0x0f) => Some(Generation::Naples),
+            (0x17, 0x30..=0x3f) => Some(Generation::Rome),
+            (0x19, 0x00..=0x0f) => Some(Generation::Milan),
+            (0x19, 0x10..=0x1f) => Some(Generation::Genoa),
+            (0x19, 0xa0..=0xaf) => Some(Generation::Bergamo),
+            (0x1a, 0x00..=0x1f) => Some(Generation::Turin),
+            _ => None,
+        }
+    }
+}
+
+/// The microcode patch level of the first CPU, as reported in /proc/cpuinfo.
+pub fn microcode(probe: &dyn Probe) -> Result<String> {
+    let cpuinfo = probe
+        .read("/proc/cpuinfo")
+        .context("unable to read /proc/cpuinfo")?;
+
+    cpuinfo
+        .lines()
+        .find_map(|line| {
+            let pos = line.find(':')?;
+            match line[..pos].trim() {
+                "microcode" => Some(line[pos + 1..].trim().to_string()),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| {
+            Context::new(
+                "/proc/cpuinfo reports no microcode patch level",
+                Box::<Error>::new(ErrorKind::NotFound.into()),
+            )
+        })
+}
+
+/// The generations the SEV certificate chain of a platform can identify.
+impl From<::sev::Generation> for Generation {
+    fn from(generation: ::sev::Generation) -> Self {
+        match generation {
+            ::sev::Generation::Naples => Generation::Naples,
+            ::sev::Generation::Rome => Generation::Rome,
+        }
+    }
+}
+
+impl fmt::Display for Generation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Profile;
+
+    #[test]
+    fn signatures_of_known_parts() {
+        let parts = [
+            (0x0080_0f12, Generation::Naples),  // EPYC 7601
+            (0x0083_0f10, Generation::Rome),    // EPYC 7302
+            (0x00a0_0f11, Generation::Milan),   // EPYC 7763
+            (0x00a1_0f11, Generation::Genoa),   // EPYC 9654
+            (0x00aa_0f01, Generation::Bergamo), // EPYC 9754
+            (0x00b0_0f21, Generation::Turin),   // EPYC 9755
+        ];
+
+        for (eax, generation) in &parts {
+            let signature = Signature::from(*eax);
+            assert_eq!(
+                Generation::from_signature(AMD, &signature),
+                Some(*generation),
+                "{}",
+                signature
+            );
+        }
+
+        let milan = Signature::from(0x00a0_0f11);
+        assert_eq!(
+            (milan.family, milan.model, milan.stepping),
+            (0x19, 0x01, 0x1)
+        );
+    }
+
+    #[test]
+    fn other_processors_are_unknown() {
+        // Intel Xeon (family 6) and Hygon Dhyana (family 0x18).
+        for eax in &[0x0005_0657, 0x0090_0f01] {
+            assert_eq!(
+                Generation::from_signature(AMD, &Signature::from(*eax)),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn vendor_is_read_from_leaf_0() {
+        assert_eq!(vendor(&Profile::with_cpu(b"AuthenticAMD", 0)), AMD);
+        assert_eq!(
+            vendor(&Profile::with_cpu(b"GenuineIntel", 0)),
+            "GenuineIntel"
+        );
+    }
+
+    #[test]
+    fn only_amd_processors_have_a_generation() {
+        let milan = 0x00a0_0f11;
+        assert_eq!(
+            Generation::detect(&Profile::with_cpu(b"AuthenticAMD", milan)).unwrap(),
+            Generation::Milan
+        );
+
+        for name in &[b"GenuineIntel", b"HygonGenuine", &[0; 12]] {
+            let host = Profile::with_cpu(name, milan);
+            assert_eq!(
+                Generation::from_signature(&vendor(&host), &Signature::from(milan)),
+                None
+            );
+            let err = Generation::detect(&host).unwrap_err();
+            assert!(
+                err.to_string().starts_with("not an AMD processor"),
+                "{}",
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,415 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decoding of the binary structures sevctl and the SEV firmware produce,
+//! for debugging attestation and launch failures.
+
+use crate::error::{Context, Contextual, Result};
+use crate::snp::{self, AttestationReport};
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use ::sev::certs::{ca, sev, Chain, Usage};
+use codicon::Decoder;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+/// Names of the certificate usages, as the certificates themselves state
+/// them.
+const USAGES: [(Usage, &str); 6] = [
+    (Usage::ARK, "ARK"),
+    (Usage::ASK, "ASK"),
+    (Usage::OCA, "OCA"),
+    (Usage::PEK, "PEK"),
+    (Usage::PDH, "PDH"),
+    (Usage::CEK, "CEK"),
+];
+
+/// Usages a SEV (rather than CA) certificate may have.
+const SEV_USAGES: [Usage; 4] = [Usage::OCA, Usage::PEK, Usage::PDH, Usage::CEK];
+
+/// Size of the LAUNCH_START session buffer.
+const SESSION_LEN: usize = 0x80;
+
+/// Serialize bytes as a hex string.
+pub fn hex_bytes<S: Serializer>(bytes: &[u8], s: S) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_str(&hex::encode(bytes))
+}
+
+// Only a single value is ever alive, so the variant sizes don't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Decoded {
+    SnpReport(AttestationReport),
+    Certificate(Certificate),
+    Godh(Certificate),
+    SevChain(SevChain),
+    CaChain(CaChain),
+    FullChain { ca: CaChain, sev: SevChain },
+    Session(Session),
+}
+
+#[derive(Serialize)]
+struct SevChain {
+    pdh: Certificate,
+    pek: Certificate,
+    oca: Certificate,
+    cek: Certificate,
+}
+
+#[derive(Serialize)]
+struct CaChain {
+    ask: Certificate,
+    ark: Certificate,
+}
+
+/// A SEV or CA certificate, as the `sev` crate describes it.
+#[derive(Serialize)]
+struct Certificate {
+    usage: &'static str,
+    summary: String,
+}
+
+#[derive(Serialize)]
+struct Session {
+    #[serde(serialize_with = "hex_bytes")]
+    nonce: Vec<u8>,
+    #[serde(serialize_with = "hex_bytes")]
+    wrap_tk: Vec<u8>,
+    #[serde(serialize_with = "hex_bytes")]
+    wrap_iv: Vec<u8>,
+    #[serde(serialize_with = "hex_bytes")]
+    wrap_mac: Vec<u8>,
+    #[serde(serialize_with = "hex_bytes")]
+    policy_mac: Vec<u8>,
+}
+
+/// Decode `file`, printing its fields unless `quiet`, in which case only
+/// the exit status tells whether the file was recognized.
+pub fn cmd(file: PathBuf, json: bool, quiet: bool) -> Result<()> {
+    let contents = fs::read(&file).context(format!("unable to read {}", file.display()))?;
+    let decoded = decode(&contents)?;
+
+    let value = serde_json::to_value(&decoded).context("unable to serialize decoded data")?;
+    if quiet {
+        return Ok(());
+    }
+    if json {
+        println!("{:#}", value);
+    } else {
+        render(&value, 0);
+    }
+
+    Ok(())
+}
+
+/// Detect the kind of structure held in `contents`, after undoing base64
+/// encoding as written by `sevctl session`. SEV-SNP reports and sessions
+/// are told apart by their size; certificates and chains must decode in
+/// full, with the usage each position calls for.
+fn decode(contents: &[u8]) -> Result<Decoded> {
+    let base64 = std::str::from_utf8(contents)
+        .ok()
+        .and_then(|s| base64::decode(s.trim()).ok());
+
+    if let Some(bytes) = base64 {
+        if bytes.len() == SESSION_LEN {
+            return Ok(Decoded::Session(session(&bytes)));
+        }
+        if let Some(godh) = whole::<sev::Certificate>(&bytes) {
+            if let Some(godh) = certificate(&godh, &[Usage::PDH]) {
+                return Ok(Decoded::Godh(godh));
+            }
+        }
+    }
+
+    match contents.len() {
+        snp::REPORT_LEN => return Ok(Decoded::SnpReport(AttestationReport::from_bytes(contents)?)),
+        SESSION_LEN => return Ok(Decoded::Session(session(contents))),
+        _ => (),
+    }
+
+    whole::<sev::Certificate>(contents)
+        .and_then(|cert| certificate(&cert, &SEV_USAGES))
+        .map(Decoded::Certificate)
+        .or_else(|| {
+            whole(contents)
+                .and_then(|c| sev_chain(&c))
+                .map(Decoded::SevChain)
+        })
+        .or_else(|| {
+            whole(contents)
+                .and_then(|c| ca_chain(&c))
+                .map(Decoded::CaChain)
+        })
+        .or_else(|| {
+            let chain = whole::<Chain>(contents)?;
+            Some(Decoded::FullChain {
+                ca: ca_chain(&chain.ca)?,
+                sev: sev_chain(&chain.sev)?,
+            })
+        })
+        .ok_or_else(|| {
+            Context::new(
+                "unrecognized file contents",
+                Box::<Error>::new(ErrorKind::InvalidData.into()),
+            )
+        })
+}
+
+/// Render a JSON value as indented `key: value` lines.
+fn render(value: &Value, level: usize) {
+    match value {
+        Value::Object(map) => {
+            for (key, val) in map {
+                match val {
+                    Value::Object(_) | Value::Array(_) => {
+                        println!("{:width$}{}:", "", key, width = level);
+                        render(val, level + 2);
+                    }
+                    _ => println!("{:width$}{}: {}", "", key, scalar(val), width = level),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                println!("{:width$}[{}]:", "", i, width = level);
+                render(item, level + 2);
+            }
+        }
+        _ => println!("{:width$}{}", "", scalar(value), width = level),
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// Decode a `T` that takes up all of `bytes`.
+fn whole<T: Decoder<(), Error = Error>>(bytes: &[u8]) -> Option<T> {
+    let mut rest = bytes;
+    let decoded = T::decode(&mut rest, ()).ok()?;
+
+    match rest {
+        [] => Some(decoded),
+        _ => None,
+    }
+}
+
+/// Describe `cert` if its usage is one of `usages`.
+fn certificate<'a, C>(cert: &'a C, usages: &[Usage]) -> Option<Certificate>
+where
+    C: fmt::Display,
+    &'a C: TryInto<Usage>,
+{
+    let usage = cert.try_into().ok()?;
+    let (_, name) = USAGES
+        .iter()
+        .find(|(u, _)| *u == usage && usages.contains(u))?;
+
+    Some(Certificate {
+        usage: name,
+        summary: cert.to_string(),
+    })
+}
+
+fn sev_chain(chain: &sev::Chain) -> Option<SevChain> {
+    Some(SevChain {
+        pdh: certificate(&chain.pdh, &[Usage::PDH])?,
+        pek: certificate(&chain.pek, &[Usage::PEK])?,
+        oca: certificate(&chain.oca, &[Usage::OCA])?,
+        cek: certificate(&chain.cek, &[Usage::CEK])?,
+    })
+}
+
+fn ca_chain(chain: &ca::Chain) -> Option<CaChain> {
+    Some(CaChain {
+        ask: certificate(&chain.ask, &[Usage::ASK])?,
+        ark: certificate(&chain.ark, &[Usage::ARK])?,
+    })
+}
+
+fn session(b: &[u8]) -> Session {
+    Session {
+        nonce: b[0x00..0x10].to_vec(),
+        wrap_tk: b[0x10..0x30].to_vec(),
+        wrap_iv: b[0x30..0x40].to_vec(),
+        wrap_mac: b[0x40..0x60].to_vec(),
+        policy_mac: b[0x60..0x80].to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EXIT_INVALID_INPUT;
+    use codicon::Encoder;
+    use serde_json::json;
+
+    fn put(b: &mut [u8], o: usize, v: u32) {
+        b[o..o + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn encode<T: Encoder<(), Error = Error>>(value: &T) -> Vec<u8> {
+        let mut bytes = vec![];
+        value.encode(&mut bytes, ()).unwrap();
+        bytes
+    }
+
+    fn sev_cert(usage: Usage) -> sev::Certificate {
+        sev::Certificate::generate(usage).unwrap().0
+    }
+
+    /// A CA certificate with a 2048-bit RSA key, laid out as in the SEV
+    /// API, appendix B.
+    fn ca_cert_bytes(usage: u32) -> Vec<u8> {
+        let mut b = vec![0u8; 0x40 + 3 * 256];
+        put(&mut b, 0x00, 1);
+        b[0x04..0x14].copy_from_slice(&[0xaa; 16]);
+        b[0x14..0x24].copy_from_slice(&[0xbb; 16]);
+        put(&mut b, 0x24, usage);
+        put(&mut b, 0x38, 2048);
+        put(&mut b, 0x3c, 2048);
+        b[0x40 + 255] = 0x03;
+        b
+    }
+
+    fn sev_chain_bytes(usages: [Usage; 4]) -> Vec<u8> {
+        usages.iter().flat_map(|u| encode(&sev_cert(*u))).collect()
+    }
+
+    fn ca_chain_bytes() -> Vec<u8> {
+        [ca_cert_bytes(0x0013), ca_cert_bytes(0x0000)].concat()
+    }
+
+    const SEV_CHAIN: [Usage; 4] = [Usage::PDH, Usage::PEK, Usage::OCA, Usage::CEK];
+
+    fn decoded(contents: &[u8]) -> Value {
+        serde_json::to_value(decode(contents).unwrap()).unwrap()
+    }
+
+    fn rejected(contents: &[u8]) -> bool {
+        match decode(contents) {
+            Ok(_) => false,
+            Err(e) => e.exit_code() == EXIT_INVALID_INPUT,
+        }
+    }
+
+    #[test]
+    fn sev_certificates_are_recognized() {
+        let pek = sev_cert(Usage::PEK);
+        assert_eq!(
+            decoded(&encode(&pek)),
+            json!({
+                "type": "certificate",
+                "usage": "PEK",
+                "summary": pek.to_string(),
+            })
+        );
+
+        let godh = base64::encode(encode(&sev_cert(Usage::PDH)));
+        let godh = decoded(godh.as_bytes());
+        assert_eq!(godh["type"], "godh");
+        assert_eq!(godh["usage"], "PDH");
+
+        let chain = decoded(&sev_chain_bytes(SEV_CHAIN));
+        assert_eq!(chain["type"], "sev_chain");
+        for (cert, usage) in &[
+            ("pdh", "PDH"),
+            ("pek", "PEK"),
+            ("oca", "OCA"),
+            ("cek", "CEK"),
+        ] {
+            assert_eq!(chain[*cert]["usage"], *usage);
+        }
+    }
+
+    #[test]
+    fn sev_certificates_must_be_valid() {
+        let mut version = encode(&sev_cert(Usage::PEK));
+        put(&mut version, 0x00, 2);
+        assert!(rejected(&version));
+
+        // A GODH is the guest owner's Diffie-Hellman key.
+        let godh = base64::encode(encode(&sev_cert(Usage::PEK)));
+        assert!(rejected(godh.as_bytes()));
+
+        let swapped = [Usage::PEK, Usage::PDH, Usage::OCA, Usage::CEK];
+        assert!(rejected(&sev_chain_bytes(swapped)));
+    }
+
+    #[test]
+    fn ca_chains_are_recognized() {
+        let chain = decoded(&ca_chain_bytes());
+        assert_eq!(chain["type"], "ca_chain");
+        assert_eq!(chain["ask"]["usage"], "ASK");
+        assert_eq!(chain["ark"]["usage"], "ARK");
+
+        let full = decoded(&[ca_chain_bytes(), sev_chain_bytes(SEV_CHAIN)].concat());
+        assert_eq!(full["type"], "full_chain");
+        assert_eq!(full["ca"]["ask"]["usage"], "ASK");
+        assert_eq!(full["sev"]["cek"]["usage"], "CEK");
+    }
+
+    #[test]
+    fn ca_certificates_must_be_valid() {
+        let mut version = ca_chain_bytes();
+        put(&mut version, 0x00, 2);
+        assert!(rejected(&version));
+
+        let swapped = [ca_cert_bytes(0x0000), ca_cert_bytes(0x0013)].concat();
+        assert!(rejected(&swapped));
+
+        let unknown = [ca_cert_bytes(0x0013), ca_cert_bytes(0x1004)].concat();
+        assert!(rejected(&unknown));
+    }
+
+    #[test]
+    fn sessions_are_recognized() {
+        let session: Vec<u8> = (0..SESSION_LEN as u8).collect();
+
+        for contents in &[session.clone(), base64::encode(&session).into_bytes()] {
+            let decoded = decoded(contents);
+            assert_eq!(decoded["type"], "session");
+            assert_eq!(decoded["nonce"], hex::encode(&session[..0x10]));
+            assert_eq!(decoded["policy_mac"], hex::encode(&session[0x60..]));
+        }
+    }
+
+    #[test]
+    fn reports_are_recognized() {
+        let mut report = vec![0u8; snp::REPORT_LEN];
+        put(&mut report, 0x00, 2);
+        put(&mut report, 0x08, 0x3_0000);
+        report[0x90..0xc0].copy_from_slice(&[0x33; 48]);
+
+        let decoded = decoded(&report);
+        assert_eq!(decoded["type"], "snp_report");
+        assert_eq!(decoded["version"], 2);
+        assert_eq!(decoded["policy"], 0x3_0000);
+        assert_eq!(decoded["measurement"], "33".repeat(48));
+    }
+
+    #[test]
+    fn partial_structures_are_rejected() {
+        let sev_cert = encode(&sev_cert(Usage::PEK));
+
+        for contents in &[
+            vec![0u8; 100],
+            sev_cert[..sev_cert.len() - 1].to_vec(),
+            [sev_cert.clone(), vec![0]].concat(),
+            ca_cert_bytes(0x0013),
+            [ca_chain_bytes(), sev_cert].concat(),
+            base64::encode(vec![0u8; 100]).into_bytes(),
+        ] {
+            assert!(rejected(contents));
+        }
+    }
+}
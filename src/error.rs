@@ -5,6 +5,64 @@
 //! normally get with simply panicking.
 
 use std::fmt;
+use std::io;
+
+use ::sev::firmware::{Error as FirmwareError, Indeterminate};
+
+/// The command failed for a reason not covered by another exit code.
+pub const EXIT_FAILURE: i32 = 1;
+/// A certificate chain, signature, measurement or probe did not verify.
+pub const EXIT_VERIFICATION: i32 = 2;
+/// A local file or device could not be read or written.
+pub const EXIT_IO: i32 = 3;
+/// A remote server could not be reached or spoke an invalid protocol, or a
+/// download was needed while network access was disabled.
+pub const EXIT_NETWORK: i32 = 4;
+/// A remote server responded with an unsuccessful HTTP status.
+pub const EXIT_HTTP: i32 = 5;
+/// The SEV firmware could not be accessed through /dev/sev.
+pub const EXIT_FIRMWARE_UNAVAILABLE: i32 = 6;
+/// An input file or argument was malformed.
+pub const EXIT_INVALID_INPUT: i32 = 7;
+/// The SEV firmware failed a command with an unknown status.
+pub const EXIT_FIRMWARE_UNKNOWN: i32 = 63;
+/// The SEV firmware failed a command; the status code is added to this.
+pub const EXIT_FIRMWARE: i32 = 64;
+
+/// SEV and SEV-SNP firmware status codes, indexed by value.
+const FIRMWARE_STATUS: [&str; 0x1f] = [
+    "SUCCESS",
+    "INVALID_PLATFORM_STATE",
+    "INVALID_GUEST_STATE",
+    "INVALID_CONFIG",
+    "INVALID_LENGTH",
+    "ALREADY_OWNED",
+    "INVALID_CERTIFICATE",
+    "POLICY_FAILURE",
+    "INACTIVE",
+    "INVALID_ADDRESS",
+    "BAD_SIGNATURE",
+    "BAD_MEASUREMENT",
+    "ASID_OWNED",
+    "INVALID_ASID",
+    "WBINVD_REQUIRED",
+    "DFFLUSH_REQUIRED",
+    "INVALID_GUEST",
+    "INVALID_COMMAND",
+    "ACTIVE",
+    "HWERROR_PLATFORM",
+    "HWERROR_UNSAFE",
+    "UNSUPPORTED",
+    "INVALID_PARAM",
+    "RESOURCE_LIMIT",
+    "SECURE_DATA_INVALID",
+    "INVALID_PAGE_SIZE",
+    "INVALID_PAGE_STATE",
+    "INVALID_MDATA_ENTRY",
+    "INVALID_PAGE_OWNER",
+    "INVALID_PAGE_AEAD_OFLOW",
+    "RMP_INIT_REQUIRED",
+];
 
 /// This implies an error that _always_ has context associated with it.
 pub type Result<T> = std::result::Result<T, Context>;
@@ -49,3 +107,120 @@ impl std::error::Error for Context {
         Some(&*self.cause)
     }
 }
+
+impl Context {
+    /// The documented process exit code for this error, determined by the
+    /// first cause of a known type.
+    pub fn exit_code(&self) -> i32 {
+        let mut err: &(dyn std::error::Error + 'static) = self;
+        while let Some(cause) = err.source() {
+            if let Some(e) = cause.downcast_ref::<Error>() {
+                return e.exit_code();
+            }
+            if let Some(e) = cause.downcast_ref::<crate::http::Error>() {
+                return match e {
+                    crate::http::Error::Status(..) => EXIT_HTTP,
+                    _ => EXIT_NETWORK,
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                return match e.kind() {
+                    io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => EXIT_INVALID_INPUT,
+                    io::ErrorKind::Other => EXIT_FAILURE,
+                    _ => EXIT_IO,
+                };
+            }
+            err = cause;
+        }
+
+        EXIT_FAILURE
+    }
+}
+
+/// Failures that automation needs to tell apart, beyond what an I/O error
+/// kind conveys.
+#[derive(Debug)]
+pub enum Error {
+    /// /dev/sev could not be opened, or the command could not be issued.
+    FirmwareIo(io::Error),
+    /// The SEV firmware failed a command with this status code.
+    Firmware(u32),
+    /// The SEV firmware failed a command with a status the sev crate doesn't know.
+    FirmwareUnknown,
+    /// A download was needed but network access is disabled.
+    Offline,
+    /// A certificate chain, signature, measurement or probe did not verify.
+    Verification,
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::FirmwareIo(_) => EXIT_FIRMWARE_UNAVAILABLE,
+            Error::Firmware(code) if (*code as usize) < FIRMWARE_STATUS.len() => {
+                EXIT_FIRMWARE + *code as i32
+            }
+            Error::Firmware(_) | Error::FirmwareUnknown => EXIT_FIRMWARE_UNKNOWN,
+            Error::Offline => EXIT_NETWORK,
+            Error::Verification => EXIT_VERIFICATION,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::FirmwareIo(_) => write!(f, "unable to access the SEV firmware"),
+            Error::Firmware(code) => match FIRMWARE_STATUS.get(*code as usize) {
+                Some(name) => write!(f, "SEV firmware error {} ({:#x})", name, code),
+                None => write!(f, "SEV firmware error {:#x}", code),
+            },
+            Error::FirmwareUnknown => write!(f, "unknown SEV firmware error"),
+            Error::Offline => write!(f, "network access is disabled"),
+            Error::Verification => write!(f, "verification failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::FirmwareIo(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Indeterminate<FirmwareError>> for Error {
+    fn from(err: Indeterminate<FirmwareError>) -> Self {
+        let err = match err {
+            Indeterminate::Known(err) => err,
+            Indeterminate::Unknown => return Error::FirmwareUnknown,
+        };
+
+        Error::Firmware(match err {
+            FirmwareError::IoError(e) => return Error::FirmwareIo(e),
+            FirmwareError::InvalidPlatformState => 0x01,
+            FirmwareError::InvalidGuestState => 0x02,
+            FirmwareError::InvalidConfig => 0x03,
+            FirmwareError::InvalidLen => 0x04,
+            FirmwareError::AlreadyOwned => 0x05,
+            FirmwareError::InvalidCertificate => 0x06,
+            FirmwareError::PolicyFailure => 0x07,
+            FirmwareError::Inactive => 0x08,
+            FirmwareError::InvalidAddress => 0x09,
+            FirmwareError::BadSignature => 0x0a,
+            FirmwareError::BadMeasurement => 0x0b,
+            FirmwareError::AsidOwned => 0x0c,
+            FirmwareError::InvalidAsid => 0x0d,
+            FirmwareError::WriteRequired => 0x0e,
+            FirmwareError::DfFlushRequired => 0x0f,
+            FirmwareError::InvalidGuest => 0x10,
+            FirmwareError::InvalidCommand => 0x11,
+            FirmwareError::Active => 0x12,
+            FirmwareError::HardwarePlatform => 0x13,
+            FirmwareError::HardwareUnsafe => 0x14,
+            FirmwareError::Unsupported => 0x15,
+        })
+    }
+}
@@ -0,0 +1,495 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The SEV platform commands sevctl issues, behind a trait so that they can
+//! be served either by the firmware through /dev/sev or by a software
+//! emulation of a SEV platform.
+
+use crate::error::{self, Context, Contextual, Result};
+use crate::snp::{self, SnpState, SnpStatus, TcbVersion};
+
+use std::cell::{RefCell, RefMut};
+use std::env;
+use std::io;
+use std::str::FromStr;
+
+use ::sev::certs::sev::{Certificate, Chain, Usage};
+use ::sev::certs::{PrivateKey, Signer, Verifiable};
+use ::sev::firmware::{
+    Build, Error as FirmwareError, Identifier, Indeterminate, PlatformStatusFlags, State, Status,
+};
+use ::sev::Version;
+
+const ENV_FIRMWARE: &str = "SEVCTL_FIRMWARE";
+
+pub type FirmwareResult<T> = std::result::Result<T, Indeterminate<FirmwareError>>;
+
+/// The SEV platform commands used by sevctl.
+pub trait Firmware {
+    fn platform_reset(&mut self) -> FirmwareResult<()>;
+    fn platform_status(&mut self) -> FirmwareResult<Status>;
+    fn pek_csr(&mut self) -> FirmwareResult<Certificate>;
+    fn pek_cert_import(&mut self, pek: &Certificate, oca: &Certificate) -> FirmwareResult<()>;
+    fn pdh_generate(&mut self) -> FirmwareResult<()>;
+    fn pdh_cert_export(&mut self) -> FirmwareResult<Chain>;
+    fn get_identifier(&mut self) -> FirmwareResult<Identifier>;
+    /// SEV-SNP status codes go beyond what the sev crate knows, so this
+    /// reports failures as sevctl errors.
+    fn snp_platform_status(&mut self) -> std::result::Result<SnpStatus, error::Error>;
+}
+
+impl Firmware for ::sev::firmware::Firmware {
+    fn platform_reset(&mut self) -> FirmwareResult<()> {
+        ::sev::firmware::Firmware::platform_reset(self)
+    }
+
+    fn platform_status(&mut self) -> FirmwareResult<Status> {
+        ::sev::firmware::Firmware::platform_status(self)
+    }
+
+    fn pek_csr(&mut self) -> FirmwareResult<Certificate> {
+        ::sev::firmware::Firmware::pek_csr(self)
+    }
+
+    fn pek_cert_import(&mut self, pek: &Certificate, oca: &Certificate) -> FirmwareResult<()> {
+        ::sev::firmware::Firmware::pek_cert_import(self, pek, oca)
+    }
+
+    fn pdh_generate(&mut self) -> FirmwareResult<()> {
+        ::sev::firmware::Firmware::pdh_generate(self)
+    }
+
+    fn pdh_cert_export(&mut self) -> FirmwareResult<Chain> {
+        ::sev::firmware::Firmware::pdh_cert_export(self)
+    }
+
+    fn get_identifier(&mut self) -> FirmwareResult<Identifier> {
+        ::sev::firmware::Firmware::get_identifier(self)
+    }
+
+    fn snp_platform_status(&mut self) -> std::result::Result<SnpStatus, error::Error> {
+        snp::device_status()
+    }
+}
+
+/// Where SEV platform commands are sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// The firmware, through /dev/sev.
+    Device,
+    /// A software emulation of a SEV platform, for testing.
+    Emulated,
+}
+
+impl FromStr for Backend {
+    type Err = Context;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "device" => Ok(Backend::Device),
+            "emulated" => Ok(Backend::Emulated),
+            _ => Err(Context::new(
+                &format!("unknown firmware backend {}", s),
+                Box::<io::Error>::new(io::ErrorKind::InvalidInput.into()),
+            )),
+        }
+    }
+}
+
+/// The selected firmware backend, opened on first use so that commands
+/// which don't need the firmware work without /dev/sev.
+pub struct Platform {
+    backend: Backend,
+    fw: RefCell<Option<Box<dyn Firmware>>>,
+}
+
+impl Platform {
+    /// Use `backend`, or else the backend named by `SEVCTL_FIRMWARE`, or
+    /// else /dev/sev.
+    pub fn new(backend: Option<Backend>) -> Result<Self> {
+        let backend = match (backend, env::var(ENV_FIRMWARE)) {
+            (Some(backend), _) => backend,
+            (None, Ok(name)) => name
+                .parse()
+                .context(format!("invalid {} setting", ENV_FIRMWARE))?,
+            (None, Err(_)) => Backend::Device,
+        };
+
+        Ok(Self {
+            backend,
+            fw: RefCell::new(None),
+        })
+    }
+
+    pub fn firmware(&self) -> Result<RefMut<'_, dyn Firmware>> {
+        let mut fw = self.fw.borrow_mut();
+
+        if fw.is_none() {
+            *fw = Some(match self.backend {
+                Backend::Device => Box::new(
+                    ::sev::firmware::Firmware::open()
+                        .map_err(error::Error::FirmwareIo)
+                        .context("unable to open /dev/sev")?,
+                ),
+                Backend::Emulated => {
+                    Box::new(Emulated::new().context("unable to start the emulated SEV firmware")?)
+                }
+            });
+        }
+
+        Ok(RefMut::map(fw, |fw| fw.as_mut().unwrap().as_mut()))
+    }
+}
+
+/// The owner-dependent keys of a SEV platform, replaced by a reset.
+struct Keys {
+    oca: Certificate,
+    pek: Certificate,
+    /// The PEK before it was signed, as returned by PEK_CSR.
+    pek_csr: Certificate,
+    pek_key: PrivateKey<Usage>,
+    pdh: Certificate,
+}
+
+impl Keys {
+    /// Generate a self-owned platform's keys: an OCA that signs itself and
+    /// the PEK, which the CEK also signs, and a PDH signed by the PEK.
+    fn generate(cek_key: &PrivateKey<Usage>) -> io::Result<Self> {
+        let (mut oca, oca_key) = Certificate::generate(Usage::OCA)?;
+        oca_key.sign(&mut oca)?;
+
+        let (mut pek, pek_key) = Certificate::generate(Usage::PEK)?;
+        let pek_csr = pek;
+        oca_key.sign(&mut pek)?;
+        cek_key.sign(&mut pek)?;
+
+        let pdh = pdh(&pek_key)?;
+
+        Ok(Self {
+            oca,
+            pek,
+            pek_csr,
+            pek_key,
+            pdh,
+        })
+    }
+}
+
+/// Generate a PDH signed by the PEK. Its private key is discarded, as
+/// sevctl never launches guests.
+fn pdh(pek_key: &PrivateKey<Usage>) -> io::Result<Certificate> {
+    let (mut pdh, _) = Certificate::generate(Usage::PDH)?;
+    pek_key.sign(&mut pdh)?;
+
+    Ok(pdh)
+}
+
+/// A SEV platform emulated in software. Its keys are generated with
+/// OpenSSL and its state only lives as long as the process; the CEK is not
+/// signed by an AMD ASK, so it is unknown to the KDS.
+pub struct Emulated {
+    build: Build,
+    guests: u32,
+    id: Identifier,
+    owned: bool,
+    cek: Certificate,
+    cek_key: PrivateKey<Usage>,
+    keys: Keys,
+}
+
+impl Emulated {
+    /// The chip identifier every emulated platform reports.
+    const ID: [u8; 64] = [0x5e; 64];
+
+    /// The SEV-SNP TCB version every emulated platform runs and reports.
+    const TCB: TcbVersion = TcbVersion {
+        bootloader: 3,
+        tee: 0,
+        snp: 8,
+        microcode: 115,
+    };
+
+    /// A freshly reset, self-owned platform without guests.
+    pub fn new() -> io::Result<Self> {
+        let (cek, cek_key) = Certificate::generate(Usage::CEK)?;
+        let keys = Keys::generate(&cek_key)?;
+
+        Ok(Self {
+            build: Build {
+                version: Version {
+                    major: 0,
+                    minor: 24,
+                },
+                build: 15,
+            },
+            guests: 0,
+            id: Identifier(Self::ID.to_vec()),
+            owned: false,
+            cek,
+            cek_key,
+            keys,
+        })
+    }
+
+    /// Fail with INVALID_PLATFORM_STATE unless the platform is in the INIT
+    /// state, i.e. has no guests.
+    fn initialized(&self) -> FirmwareResult<()> {
+        if self.guests > 0 {
+            return Err(Indeterminate::Known(FirmwareError::InvalidPlatformState));
+        }
+
+        Ok(())
+    }
+}
+
+fn io_error(e: io::Error) -> Indeterminate<FirmwareError> {
+    Indeterminate::Known(FirmwareError::IoError(e))
+}
+
+fn invalid_certificate<T>(_: T) -> Indeterminate<FirmwareError> {
+    Indeterminate::Known(FirmwareError::InvalidCertificate)
+}
+
+impl Firmware for Emulated {
+    fn platform_reset(&mut self) -> FirmwareResult<()> {
+        self.initialized()?;
+
+        self.keys = Keys::generate(&self.cek_key).map_err(io_error)?;
+        self.owned = false;
+
+        Ok(())
+    }
+
+    fn platform_status(&mut self) -> FirmwareResult<Status> {
+        // Like every processor since Rome, the emulated one supports SEV-ES.
+        let mut flags = PlatformStatusFlags::ENCRYPTED_STATE;
+        if self.owned {
+            flags |= PlatformStatusFlags::OWNED;
+        }
+
+        Ok(Status {
+            build: self.build,
+            state: match self.guests {
+                0 => State::Initialized,
+                _ => State::Working,
+            },
+            flags,
+            guests: self.guests,
+        })
+    }
+
+    fn pek_csr(&mut self) -> FirmwareResult<Certificate> {
+        self.initialized()?;
+
+        Ok(self.keys.pek_csr)
+    }
+
+    fn pek_cert_import(&mut self, pek: &Certificate, oca: &Certificate) -> FirmwareResult<()> {
+        self.initialized()?;
+        if self.owned {
+            return Err(Indeterminate::Known(FirmwareError::AlreadyOwned));
+        }
+
+        // The OCA must sign itself and the PEK, and the PEK must hold this
+        // platform's key, which signs the current PDH.
+        (oca, oca).verify().map_err(invalid_certificate)?;
+        (oca, pek).verify().map_err(invalid_certificate)?;
+        (pek, &self.keys.pdh)
+            .verify()
+            .map_err(invalid_certificate)?;
+
+        let mut pek = *pek;
+        self.cek_key.sign(&mut pek).map_err(invalid_certificate)?;
+
+        self.keys.oca = *oca;
+        self.keys.pek = pek;
+        self.owned = true;
+
+        Ok(())
+    }
+
+    fn pdh_generate(&mut self) -> FirmwareResult<()> {
+        self.initialized()?;
+
+        self.keys.pdh = pdh(&self.keys.pek_key).map_err(io_error)?;
+
+        Ok(())
+    }
+
+    fn pdh_cert_export(&mut self) -> FirmwareResult<Chain> {
+        Ok(Chain {
+            pdh: self.keys.pdh,
+            pek: self.keys.pek,
+            oca: self.keys.oca,
+            cek: self.cek,
+        })
+    }
+
+    fn get_identifier(&mut self) -> FirmwareResult<Identifier> {
+        Ok(self.id.clone())
+    }
+
+    fn snp_platform_status(&mut self) -> std::result::Result<SnpStatus, error::Error> {
+        Ok(SnpStatus {
+            api_major: 1,
+            api_minor: 55,
+            build: 21,
+            state: SnpState::Initialized,
+            rmp_initialized: true,
+            guests: 0,
+            current_tcb: Self::TCB,
+            reported_tcb: Self::TCB,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codicon::Encoder;
+
+    fn bytes(cert: &Certificate) -> Vec<u8> {
+        let mut bytes = vec![];
+        cert.encode(&mut bytes, ()).unwrap();
+        bytes
+    }
+
+    fn known(result: FirmwareResult<()>) -> FirmwareError {
+        match result {
+            Err(Indeterminate::Known(e)) => e,
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    /// Sign the platform's PEK with a new OCA, as `sevctl provision` does.
+    fn owner(fw: &mut Emulated) -> (Certificate, Certificate) {
+        let (mut oca, oca_key) = Certificate::generate(Usage::OCA).unwrap();
+        oca_key.sign(&mut oca).unwrap();
+
+        let mut pek = fw.pek_csr().unwrap();
+        oca_key.sign(&mut pek).unwrap();
+
+        (pek, oca)
+    }
+
+    fn assert_chain_valid(chain: &Chain) {
+        (&chain.oca, &chain.oca).verify().unwrap();
+        (&chain.oca, &chain.pek).verify().unwrap();
+        (&chain.cek, &chain.pek).verify().unwrap();
+        (&chain.pek, &chain.pdh).verify().unwrap();
+    }
+
+    #[test]
+    fn starts_self_owned() {
+        let mut fw = Emulated::new().unwrap();
+
+        let status = fw.platform_status().unwrap();
+        assert_eq!(status.state, State::Initialized);
+        assert_eq!(status.guests, 0);
+        assert!(!status.flags.contains(PlatformStatusFlags::OWNED));
+        assert_eq!(fw.get_identifier().unwrap().0, Emulated::ID.to_vec());
+
+        assert_chain_valid(&fw.pdh_cert_export().unwrap());
+    }
+
+    #[test]
+    fn snp_status_comes_from_the_backend() {
+        let platform = Platform::new(Some(Backend::Emulated)).unwrap();
+
+        let status = snp::platform_status(&platform).unwrap();
+        assert!(status.initialized());
+        assert!(status.rmp_initialized);
+        assert_eq!(
+            (status.api_major, status.api_minor, status.build),
+            (1, 55, 21)
+        );
+        assert_eq!(status.current_tcb, Emulated::TCB);
+        assert_eq!(
+            status.reported_tcb.to_string(),
+            "bootloader=3 tee=0 snp=8 microcode=115"
+        );
+    }
+
+    #[test]
+    fn provisioning_takes_ownership() {
+        let mut fw = Emulated::new().unwrap();
+        let (pek, oca) = owner(&mut fw);
+
+        fw.pek_cert_import(&pek, &oca).unwrap();
+
+        let status = fw.platform_status().unwrap();
+        assert!(status.flags.contains(PlatformStatusFlags::OWNED));
+
+        let chain = fw.pdh_cert_export().unwrap();
+        assert_eq!(bytes(&chain.oca), bytes(&oca));
+        assert_chain_valid(&chain);
+
+        let (pek, oca) = owner(&mut fw);
+        let err = fw.pek_cert_import(&pek, &oca);
+        assert!(matches!(known(err), FirmwareError::AlreadyOwned));
+    }
+
+    #[test]
+    fn import_rejects_foreign_certificates() {
+        let mut fw = Emulated::new().unwrap();
+        let mut other = Emulated::new().unwrap();
+
+        // A PEK that the OCA didn't sign.
+        let (_, oca) = owner(&mut fw);
+        let pek = fw.pek_csr().unwrap();
+        let err = fw.pek_cert_import(&pek, &oca);
+        assert!(matches!(known(err), FirmwareError::InvalidCertificate));
+
+        // Another platform's PEK.
+        let (pek, oca) = owner(&mut other);
+        let err = fw.pek_cert_import(&pek, &oca);
+        assert!(matches!(known(err), FirmwareError::InvalidCertificate));
+
+        assert!(!fw
+            .platform_status()
+            .unwrap()
+            .flags
+            .contains(PlatformStatusFlags::OWNED));
+    }
+
+    #[test]
+    fn rotation_replaces_the_pdh() {
+        let mut fw = Emulated::new().unwrap();
+        let before = fw.pdh_cert_export().unwrap();
+
+        fw.pdh_generate().unwrap();
+
+        let after = fw.pdh_cert_export().unwrap();
+        assert_ne!(bytes(&before.pdh), bytes(&after.pdh));
+        assert_eq!(bytes(&before.pek), bytes(&after.pek));
+        assert_chain_valid(&after);
+    }
+
+    #[test]
+    fn reset_drops_ownership_and_keys() {
+        let mut fw = Emulated::new().unwrap();
+        let (pek, oca) = owner(&mut fw);
+        fw.pek_cert_import(&pek, &oca).unwrap();
+
+        fw.platform_reset().unwrap();
+
+        let status = fw.platform_status().unwrap();
+        assert!(!status.flags.contains(PlatformStatusFlags::OWNED));
+
+        let chain = fw.pdh_cert_export().unwrap();
+        assert_ne!(bytes(&chain.oca), bytes(&oca));
+        assert_ne!(bytes(&fw.pek_csr().unwrap()), bytes(&pek));
+        assert_chain_valid(&chain);
+    }
+
+    #[test]
+    fn running_guests_block_platform_commands() {
+        let mut fw = Emulated::new().unwrap();
+        fw.guests = 1;
+        assert_eq!(fw.platform_status().unwrap().state, State::Working);
+
+        let err = fw.platform_reset();
+        assert!(matches!(known(err), FirmwareError::InvalidPlatformState));
+        let err = fw.pdh_generate();
+        assert!(matches!(known(err), FirmwareError::InvalidPlatformState));
+    }
+}
@@ -19,6 +19,49 @@
 //! $ sevctl show --help
 //! ```
 //!
+//! ## JSON output
+//!
+//! Every subcommand can emit a single JSON document on standard output instead of text with the global
+//! `--output json` option, for driving `sevctl` from other programs. Commands that write files report the
+//! paths they wrote, and verification commands report the result of each check. On failure, the error
+//! and its chain of causes are printed as JSON to standard error.
+//!
+//! ```console
+//! $ sevctl --output json show all
+//! $ sevctl --output json verify
+//! ```
+//!
+//! ## cache
+//!
+//! Certificates downloaded from the AMD Key Distribution Service (the CEK for `export` and `verify`, and
+//! the SEV-SNP ARK/ASK chain and VCEK) are kept in a local cache keyed by chip identifier, by default in
+//! `~/.cache/sevctl`, or `/var/cache/sevctl` when run as root. `--cache-dir` selects another directory,
+//! and `--offline` refuses any network access so that only cached certificates are used.
+//!
+//! For hosts without network access, certificates can be downloaded on a connected machine and imported
+//! into the cache. The chip identifier and processor product default to those of the local platform.
+//!
+//! ```console
+//! $ sevctl cache import cek --id <chip id> /path/to/cek.cert
+//! $ sevctl cache import chain --product milan /path/to/cert_chain.pem
+//! $ sevctl cache import vcek /path/to/vcek.der
+//! $ sevctl cache list
+//! $ sevctl cache prune --older-than 30
+//! $ sevctl --offline verify
+//! ```
+//!
+//! ## decode
+//!
+//! Prints the fields of a SEV-SNP attestation report, a SEV certificate, a SEV or CA certificate chain,
+//! or the GODH and launch session files written by `sevctl session`. The type of the file is detected
+//! automatically, and certificates with an unknown version, or a usage other than the one their place
+//! calls for, are rejected; `--json` prints the fields as JSON instead.
+//!
+//! ```console
+//! $ sevctl decode /path/to/report.bin
+//! $ sevctl decode --json vm_godh.b64
+//! ```
+//!
 //! ## export
 //!
 //! Exports the SEV certificate chain to the provided file path.
@@ -35,15 +78,79 @@
 //! $ sevctl generate ~/my-cert ~/my-key
 //! ```
 //!
+//! ## measurement
+//!
+//! Computes the launch measurement a SEV guest is expected to report, using the TIK written by
+//! `sevctl session`. The result is base64-encoded as measurement || mnonce, the same layout QEMU
+//! returns from `query-sev-launch-measure`.
+//!
+//! ```console
+//! $ sevctl measurement build --tik vm_tik.bin --ovmf OVMF.fd --api-major 0 --api-minor 24 \
+//!     --build-id 15 --policy 3 --nonce {base64 mnonce}
+//! ```
+//!
+//! A measurement returned by QEMU can be checked against the expected firmware (or a precomputed
+//! `--launch-digest`); a mismatch exits with a non-zero status.
+//!
+//! ```console
+//! $ sevctl measurement verify --tik vm_tik.bin --ovmf OVMF.fd --api-major 0 --api-minor 24 \
+//!     --build-id 15 --policy 3 --measurement {base64 measurement}
+//! ```
+//!
 //! ## ok
 //!
 //! Probes processor, sysfs, and KVM for AMD SEV, SEV-ES, and SEV-SNP related features on the host and emits the results.
+//! For SEV-ES, this includes checking that KVM enables SEV-ES, that ASIDs are set aside for SEV-ES guests, and that the
+//! SEV firmware supports SEV-ES. For SEV-SNP, this includes checking that the BIOS enabled SEV-SNP and reserved the RMP table, that the IOMMU is
+//! enabled, and that KVM and the SEV firmware have initialized SEV-SNP. Reading the SYSCFG and RMP MSRs requires root
+//! and the `msr` kernel module.
 //!
 //! ```console
 //! $ sevctl ok {sev, es, snp}   // Probes support for the generation specified.
 //! $ sevctl ok                  // Probes support for the host hardware's generation.
 //! ```
 //!
+//! The results can also be emitted as JSON, including each test's name, state, message, and
+//! sub-tests, for aggregation across many hosts.
+//!
+//! ```console
+//! $ sevctl ok --format json snp
+//! ```
+//!
+//! Everything `ok` probes (CPUID leaves, MSRs, files, device nodes, resource limits, and the firmware
+//! status) can be recorded to a host profile, for example to attach to a bug report. `ok` can then run
+//! against the profile instead of the host it runs on, which reproduces the results of the recorded host
+//! anywhere.
+//!
+//! ```console
+//! $ sevctl ok --record host.json
+//! $ sevctl ok --profile host.json snp
+//! ```
+//!
+//! `ok` can also serve as an admission gate: a rules file sets thresholds that the results of some tests
+//! must meet, such as the memlock limits, the number of encrypted guests, the SEV firmware version, and
+//! the SEV-SNP TCB. Each rule is optional and written as `key = value`, like the configuration file.
+//!
+//! ```console
+//! $ cat rules
+//! min-memlock-soft = unlimited
+//! min-encrypted-guests = 500
+//! min-firmware = 1.55.21
+//! min-snp-tcb = bootloader=3 tee=0 snp=8 microcode=115
+//! $ sevctl ok --rules rules
+//! ```
+//!
+//! ## policy
+//!
+//! Decodes a guest launch policy into its individual flags and minimum firmware API version. The policy
+//! may be given as an integer or as a comma-separated list of flags, which also makes this a handy way
+//! to compute the integer value.
+//!
+//! ```console
+//! $ sevctl policy decode 0x110003
+//! $ sevctl policy decode nodbg,noks,es,api-major=0,api-minor=17
+//! ```
+//!
 //! ## provision
 //!
 //! Installs the operator-provided OCA certificate to take ownership of the platform.
@@ -68,17 +175,40 @@
 //! $ sevctl rotate
 //! ```
 //!
+//! ## secret
+//!
+//! Packages guest secrets for injection with QMP `sev-inject-launch-secret`. Each `--secret` pairs a
+//! GUID with the file holding its value. The secret table is encrypted with the TEK and authenticated
+//! with the TIK written by `sevctl session`, and bound to the launch measurement returned by QEMU.
+//! The base64-encoded packet header and secret are written to `{name}_secret_header.b64` and
+//! `{name}_secret.b64`.
+//!
+//! ```console
+//! $ sevctl secret build --name {name} --tek vm_tek.bin --tik vm_tik.bin \
+//!     --measurement {base64 measurement} --secret {guid}={/path/to/passphrase}
+//! ```
+//!
 //! ## session
 //!
-//! Given a certificate chain file and 32-bit policy, generates base64-encoded GODH and launch session files; as
-//! well as encoded (not base64) TIK and TEK files.
+//! Given a certificate chain file and launch policy, generates base64-encoded GODH and launch session files; as
+//! well as encoded (not base64) TIK and TEK files. The policy may be an integer, a comma-separated list of flags
+//! (`nodbg`, `noks`, `es`, `nosend`, `domain`, `sev`, `api-major=N`, `api-minor=N`), or built from named options.
 //!
 //! ```console
 //! $ sevctl session --name {name} {/pdh/cert/path} {policy}
+//! $ sevctl session --name {name} {/pdh/cert/path} nodbg,noks,es
+//! $ sevctl session --name {name} --no-debug --es --api-major 0 --api-minor 17 {/pdh/cert/path}
 //! ```
 //! ## show
 //!
-//! Describes the state of the SEV platform.
+//! Describes the state of the SEV platform. `show all` gathers what support requests usually ask for in one
+//! view: the firmware API version and build, the platform state and owner, whether SEV-ES is available, the
+//! guest count, the chip identifier, the processor generation and microcode patch level and, on SEV-SNP
+//! capable hosts, the SNP platform status and TCB versions.
+//!
+//! ```console
+//! $ sevctl show all
+//! ```
 //!
 //! ```console
 //! $ sevctl show flags
@@ -88,6 +218,51 @@
 //! $ sevctl show guests
 //! ```
 //!
+//! The processor generation (Naples, Rome, Milan, Genoa, Bergamo or Turin) is derived from the CPUID family
+//! and model of AMD processors. Where the SEV certificate chain of the platform identifies a generation,
+//! the two must agree; only a CEK that is already cached is used for this, so nothing is downloaded.
+//!
+//! ```console
+//! $ sevctl show generation
+//! ```
+//!
+//! On SEV-SNP capable hosts, the SNP platform state, guest count and TCB versions are reported by
+//!
+//! ```console
+//! $ sevctl show snp
+//! ```
+//!
+//! ## snp
+//!
+//! Verifies a SEV-SNP attestation report: its signature is checked against the VCEK (read from a file, or
+//! fetched from the AMD KDS for the chip and TCB named in the report and checked against the ASK and ARK),
+//! the reported TCB against the VCEK, and optionally the measurement, host data, report data and policy
+//! against expected values. Any mismatch exits with a non-zero status, and malformed expected values
+//! with status 7. A VCEK read from a file can be checked with `sevctl vcek verify`.
+//!
+//! ```console
+//! $ sevctl snp verify-report --vcek /path/to/vcek.der --measurement {hex} /path/to/report.bin
+//! ```
+//!
+//! ## vcek
+//!
+//! Fetches the SEV-SNP Versioned Chip Endorsement Key (VCEK) of this platform from the AMD Key
+//! Distribution Service, using the chip identifier and reported TCB, and writes it to the provided file
+//! path in DER format. The product name (Milan, Genoa) is detected from the processor unless given.
+//!
+//! ```console
+//! $ sevctl vcek fetch /path/to/vcek.der
+//! $ sevctl vcek fetch --product Milan /path/to/vcek.der
+//! ```
+//!
+//! The ARK/ASK chain of the product line can be exported in PEM format, and the VCEK verified against it.
+//! Either may be read from a file; otherwise it is downloaded.
+//!
+//! ```console
+//! $ sevctl vcek chain /path/to/cert_chain.pem
+//! $ sevctl vcek verify --chain /path/to/cert_chain.pem --vcek /path/to/vcek.der
+//! ```
+//!
 //! ## verify
 //!
 //! Verifies the full SEV/CA certificate chain. File paths to these certificates can be supplied as
@@ -97,6 +272,79 @@
 //! ```console
 //! $ sevctl verify
 //! ```
+//!
+//! # Network access
+//!
+//! Certificates are downloaded from the AMD Key Distribution Service over HTTPS. Behind a proxy, set
+//! `HTTPS_PROXY` (or `https_proxy`) to the proxy's `http://[user:password@]host:port` URL; requests are
+//! tunneled with HTTP CONNECT. An `http://` `--kds-url` uses `HTTP_PROXY` (or `http_proxy`) instead, and
+//! its requests are sent to the proxy itself. Hosts listed in the comma-separated `NO_PROXY` (or
+//! `no_proxy`) are contacted directly, where an entry matches a host name or any of its subdomains and `*`
+//! matches every host.
+//!
+//! ```console
+//! $ HTTPS_PROXY=http://proxy.example.com:3128 NO_PROXY=localhost,.internal sevctl verify
+//! ```
+//!
+//! Connections time out after 10 seconds, and reads after 30 seconds without data. Up to five
+//! redirects are followed, but not from HTTPS to HTTP or any other scheme.
+//!
+//! Downloads that fail with HTTP 429, a 5xx status or a broken connection are retried 3 times, waiting
+//! 1 second and then twice as long before each further attempt, up to 60 seconds, plus a random delay
+//! of up to 1 second. A `Retry-After` header replaces the exponential delay. A missing certificate
+//! (HTTP 404) fails immediately. Set these with `--retries`, `--max-backoff` and `--retry-jitter`,
+//! `SEVCTL_RETRIES`, `SEVCTL_MAX_BACKOFF` and `SEVCTL_RETRY_JITTER`, or the `retries`, `max-backoff`
+//! and `retry-jitter` configuration file settings.
+//!
+//! The KDS base URL defaults to `https://kdsintf.amd.com`, and HTTPS servers are verified against the
+//! system trust anchors. To use a KDS mirror, set the base URL with `--kds-url` or `SEVCTL_KDS_URL`,
+//! and add the PEM certificates of a private CA with `--ca-file` (repeatable) or `SEVCTL_CA_FILE`
+//! (a `:`-separated list). Both can also be set in a configuration file, read from `--config`,
+//! `SEVCTL_CONFIG`, `~/.config/sevctl/config` or `/etc/sevctl/config`. Command line options take
+//! precedence over the environment, which takes precedence over the configuration file.
+//! These settings are only read when a certificate is downloaded, so a malformed one does not affect
+//! commands that work offline.
+//!
+//! ```text
+//! # /etc/sevctl/config
+//! kds-url = https://kds.example.com
+//! ca-file = /etc/pki/example-ca.pem
+//! retries = 5
+//! ```
+//!
+//! # Emulated firmware
+//!
+//! `--firmware emulated` (or `SEVCTL_FIRMWARE=emulated`) sends the SEV and SEV-SNP commands of `export`,
+//! `ok`, `provision`, `reset`, `rotate`, `show`, `snp`, `vcek` and `verify` to a software emulation of a
+//! SEV platform instead of `/dev/sev`, so that they can be tried out and tested without an EPYC host. The
+//! emulated platform generates its keys with OpenSSL and keeps its state only for the lifetime of the
+//! command. Its CEK is not signed by AMD and is unknown to the KDS, so commands that need the CEK must
+//! find it in the cache. It reports an initialized SEV-SNP firmware 1.55.21 at TCB version bootloader=3
+//! tee=0 snp=8 microcode=115.
+//!
+//! ```console
+//! $ sevctl --firmware emulated show all
+//! ```
+//!
+//! # Exit codes
+//!
+//! `sevctl` exits with 0 on success. Failures exit with a code describing their cause, so that scripts
+//! can tell them apart:
+//!
+//! | Code    | Meaning                                                                 |
+//! |---------|-------------------------------------------------------------------------|
+//! | 1       | Any other failure                                                       |
+//! | 2       | A certificate chain, signature, measurement or `sevctl ok` check failed |
+//! | 3       | A local file or device could not be read or written                     |
+//! | 4       | A remote server could not be reached, or `--offline` forbade a download |
+//! | 5       | A remote server responded with an unsuccessful HTTP status              |
+//! | 6       | The SEV firmware could not be accessed through `/dev/sev`               |
+//! | 7       | An input file or argument was malformed                                 |
+//! | 63      | The SEV firmware failed a command with an unknown status                |
+//! | 64 + n  | The SEV firmware failed a command with status n                         |
+//!
+//! Firmware statuses include INVALID_PLATFORM_STATE (65), ALREADY_OWNED (69), INVALID_CERTIFICATE (70)
+//! and BAD_SIGNATURE (74); see the SEV and SEV-SNP firmware ABI specifications for the full list.
 
 #![deny(clippy::all)]
 #![deny(missing_docs)]
@@ -105,13 +353,14 @@ mod error;
 mod http;
 
 use error::{Contextual, Result};
+use output::Output;
 
 use structopt::StructOpt;
 
 use codicon::*;
 
 use ::sev::certs::*;
-use ::sev::firmware::{Firmware, PlatformStatusFlags, Status};
+use ::sev::firmware::{PlatformStatusFlags, Status};
 use ::sev::Generation;
 
 use std::fs::File;
@@ -120,8 +369,21 @@ use std::path::PathBuf;
 use std::process::exit;
 use std::time::Duration;
 
+mod cache;
+mod config;
+mod cpu;
+mod decode;
+mod firmware;
+mod measurement;
 mod ok;
+mod output;
+mod policy;
+mod probe;
+mod rules;
+mod secret;
 mod session;
+mod snp;
+mod vcek;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
@@ -133,11 +395,88 @@ struct Sevctl {
 
     #[structopt(short, long, help = "Don't print anything to the console")]
     pub quiet: bool,
+
+    #[structopt(
+        short,
+        long,
+        conflicts_with = "quiet",
+        possible_values = &["text", "json"],
+        help = "Output format (default: text)"
+    )]
+    pub output: Option<output::Output>,
+
+    #[structopt(long, help = "Never access the network; use only cached certificates")]
+    pub offline: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Certificate cache directory (default: ~/.cache/sevctl, or /var/cache/sevctl for root)"
+    )]
+    pub cache_dir: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Configuration file (default: ~/.config/sevctl/config or /etc/sevctl/config)"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[structopt(long, help = "Base URL of the AMD Key Distribution Service")]
+    pub kds_url: Option<String>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        number_of_values = 1,
+        help = "Also trust the CA certificates in this PEM file for HTTPS (repeatable)"
+    )]
+    pub ca_file: Vec<PathBuf>,
+
+    #[structopt(long, help = "Retry failed downloads this many times (default: 3)")]
+    pub retries: Option<u32>,
+
+    #[structopt(
+        long,
+        help = "Longest delay between download attempts in seconds (default: 60)"
+    )]
+    pub max_backoff: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Add a random delay of up to this many seconds between download attempts (default: 1)"
+    )]
+    pub retry_jitter: Option<u64>,
+
+    #[structopt(
+        long,
+        possible_values = &["device", "emulated"],
+        help = "Send SEV commands to /dev/sev or to a software emulation for testing (default: device)"
+    )]
+    pub firmware: Option<firmware::Backend>,
 }
 
 #[derive(StructOpt)]
 #[structopt(author = AUTHORS, version = VERSION, about = "Utilities for managing the SEV environment")]
 enum SevctlCmd {
+    #[structopt(about = "Manage the local cache of downloaded certificates")]
+    Cache {
+        #[structopt(subcommand)]
+        cmd: cache::CacheCmd,
+    },
+
+    #[structopt(about = "Decode and print SEV and SEV-SNP binary structures")]
+    Decode {
+        #[structopt(long, help = "Print the decoded fields as JSON")]
+        json: bool,
+
+        #[structopt(
+            parse(from_os_str),
+            help = "Path of an attestation report, certificate, chain, GODH or session file"
+        )]
+        file: PathBuf,
+    },
+
     #[structopt(about = "Export the SEV or entire certificate chain")]
     Export {
         #[structopt(
@@ -160,10 +499,50 @@ enum SevctlCmd {
         key: PathBuf,
     },
 
+    #[structopt(about = "Compute SEV launch measurements")]
+    Measurement {
+        #[structopt(subcommand)]
+        cmd: measurement::MeasurementCmd,
+    },
+
     #[structopt(about = "Probe system for SEV support")]
     Ok {
         #[structopt(subcommand)]
         gen: Option<ok::SevGeneration>,
+
+        #[structopt(
+            long,
+            possible_values = &["text", "json"],
+            help = "Output format of the test results (default: --output)"
+        )]
+        format: Option<output::Output>,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Probe a host profile recorded with --record instead of this host"
+        )]
+        profile: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Record everything probed to this file as a host profile"
+        )]
+        record: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Also fail tests whose results fall short of the thresholds in this file"
+        )]
+        rules: Option<PathBuf>,
+    },
+
+    #[structopt(about = "Inspect SEV guest launch policies")]
+    Policy {
+        #[structopt(subcommand)]
+        cmd: policy::PolicyCmd,
     },
 
     #[structopt(about = "Take ownership of the SEV platform")]
@@ -181,6 +560,12 @@ enum SevctlCmd {
     #[structopt(about = "Rotate PDH")]
     Rotate,
 
+    #[structopt(about = "Package secrets for SEV launch secret injection")]
+    Secret {
+        #[structopt(subcommand)]
+        cmd: secret::SecretCmd,
+    },
+
     #[structopt(about = "Generate a SEV launch session")]
     Session {
         #[structopt(short, long, help = "Name used to identify file names")]
@@ -192,8 +577,8 @@ enum SevctlCmd {
         )]
         pdh: PathBuf,
 
-        #[structopt(help = "32-bit integer representing the launch policy")]
-        policy: u32,
+        #[structopt(flatten)]
+        policy: policy::PolicyArgs,
     },
 
     #[structopt(about = "Display information about the SEV platform")]
@@ -202,6 +587,18 @@ enum SevctlCmd {
         cmd: show::Show,
     },
 
+    #[structopt(about = "SEV-SNP attestation utilities")]
+    Snp {
+        #[structopt(subcommand)]
+        cmd: snp::SnpCmd,
+    },
+
+    #[structopt(about = "Manage SEV-SNP VCEK certificates")]
+    Vcek {
+        #[structopt(subcommand)]
+        cmd: vcek::VcekCmd,
+    },
+
     #[structopt(about = "Verify certificate chain")]
     Verify {
         #[structopt(long, parse(from_os_str), help = "Read SEV chain from specified file")]
@@ -219,187 +616,502 @@ enum SevctlCmd {
     },
 }
 
-fn download(url: &str, usage: Usage) -> Result<sev::Certificate> {
+fn fetch(
+    url: &str,
+    roots: &[native_tls::Certificate],
+    retry: &config::Retry,
+) -> Result<http::Response> {
     use error::Context;
-    use std::error::Error;
 
-    let mut err_stack = vec![];
+    let mut attempts = vec![];
 
-    for attempt in 1..4 {
-        match http::get(url) {
-            Ok(rsp) => {
-                return sev::Certificate::decode(rsp.into_reader(), ())
-                    .context(format!("failed to decode {} certificate", usage))
+    loop {
+        let err = match http::get(url, roots) {
+            Ok(rsp) => return Ok(rsp),
+            Err(e) if e.is_transient() && attempts.len() < retry.retries as usize => e,
+            Err(e) if attempts.is_empty() => {
+                return Err(Context::new("http request failed", Box::new(e)))
             }
-            Err(http::Error::Status(_, rsp)) => {
-                err_stack.push(Context::new(
-                    format!("http request #{} failed", attempt).as_str(),
-                    format!("{:?}", rsp).into(),
-                ));
-                let retry: Option<u16> = rsp.header("retry-after").and_then(|h| h.parse().ok());
-                let retry = retry.unwrap_or(5);
-                std::thread::sleep(Duration::from_secs(retry as _));
+            Err(e) => {
+                return Err(Context::new(
+                    &format!(
+                        "final http request failed (previous attempts: {})",
+                        attempts.join("; ")
+                    ),
+                    Box::new(e),
+                ))
             }
-            Err(e) => return Err(Context::new("transport error", Box::new(e))),
-        }
+        };
+
+        let retry_after = match &err {
+            http::Error::Status(_, rsp) => rsp.retry_after(),
+            _ => None,
+        };
+        let delay = backoff(attempts.len(), retry_after, retry.max_backoff) + jitter(retry.jitter);
+
+        attempts.push(format!(
+            "http request #{} failed: {}",
+            attempts.len() + 1,
+            err
+        ));
+        std::thread::sleep(delay);
     }
+}
 
-    // One last attempt before giving up
-    let rsp = http::get(url).map_err(|e| {
-        let prev_attempts = err_stack
-            .into_iter()
-            .map(|e| {
-                let cause = match e.source() {
-                    Some(c) => format!("{}", c),
-                    None => "".to_string(),
-                };
-                format!("{}: {}", e, cause)
-            })
-            .collect::<Vec<String>>();
-        Context::new(
-            &format!("final http request failed: {}", e),
-            prev_attempts.join("; ").into(),
-        )
-    })?;
+/// How long to wait before retrying after `retries` earlier retries: what
+/// the server asked for, or else one second doubled for each earlier retry,
+/// but never longer than `max`.
+fn backoff(retries: usize, retry_after: Option<Duration>, max: Duration) -> Duration {
+    let exponential = 1u64.checked_shl(retries as u32).unwrap_or(u64::MAX);
 
-    sev::Certificate::decode(rsp.into_reader(), ())
-        .context(format!("failed to decode {} certificate", usage))
+    retry_after
+        .unwrap_or_else(|| Duration::from_secs(exponential))
+        .min(max)
 }
 
-fn firmware() -> Result<Firmware> {
-    Firmware::open().context("unable to open /dev/sev")
+/// A random duration of up to `max`.
+fn jitter(max: Duration) -> Duration {
+    let mut buf = [0u8; 8];
+    if max == Duration::default() || openssl::rand::rand_bytes(&mut buf).is_err() {
+        return Duration::default();
+    }
+
+    let millis = max.as_millis() as u64;
+    Duration::from_millis(u64::from_ne_bytes(buf) % (millis + 1))
+}
+
+fn download(
+    cache: &cache::Cache,
+    key: &std::path::Path,
+    url: &str,
+    usage: Usage,
+) -> Result<sev::Certificate> {
+    cache.fetch(key, url, |contents| {
+        sev::Certificate::decode(&mut &contents[..], ())
+            .context(format!("failed to decode {} certificate", usage))
+    })
 }
 
-fn platform_status() -> Result<Status> {
-    firmware()?
+fn platform_status(platform: &firmware::Platform) -> Result<Status> {
+    platform
+        .firmware()?
         .platform_status()
-        .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
+        .map_err(error::Error::from)
         .context("unable to fetch platform status")
 }
 
-fn chain() -> Result<sev::Chain> {
-    const CEK_SVC: &str = "https://kdsintf.amd.com/cek/id";
+/// This platform's chip identifier, as hex.
+fn chip_id(platform: &firmware::Platform) -> Result<String> {
+    let id = platform
+        .firmware()?
+        .get_identifier()
+        .map_err(error::Error::from)
+        .context("error fetching identifier")?;
+
+    Ok(id.to_string())
+}
+
+fn chain(platform: &firmware::Platform, cache: &cache::Cache) -> Result<sev::Chain> {
+    const CEK_SVC: &str = "/cek/id";
 
-    let mut chain = firmware()?
+    let mut chain = platform
+        .firmware()?
         .pdh_cert_export()
-        .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
+        .map_err(error::Error::from)
         .context("unable to export SEV certificates")?;
 
-    let id = firmware()?
-        .get_identifier()
-        .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
-        .context("error fetching identifier")?;
+    let id = chip_id(platform)?;
     let url = format!("{}/{}", CEK_SVC, id);
 
-    chain.cek = download(&url, Usage::CEK)?;
+    chain.cek = download(cache, &cache::cek_key(&id)?, &url, Usage::CEK)?;
 
     Ok(chain)
 }
 
+/// The platform's certificate chain, if its CEK is already cached.
+fn cached_chain(platform: &firmware::Platform, cache: &cache::Cache) -> Option<sev::Chain> {
+    let mut chain = platform.firmware().ok()?.pdh_cert_export().ok()?;
+
+    let id = chip_id(platform).ok()?;
+    chain.cek = cache.cached(&cache::cek_key(&id).ok()?, |contents| {
+        sev::Certificate::decode(&mut &contents[..], ()).context("failed to decode CEK certificate")
+    })?;
+
+    Some(chain)
+}
+
 fn ca_chain_builtin(chain: &sev::Chain) -> Result<ca::Chain> {
+    use error::Context;
     use std::convert::TryFrom;
 
-    Generation::try_from(chain)
-        .map_err(|_| {
-            Error::new(
-                ErrorKind::NotFound,
-                "could not find a matching builtin certificate",
-            )
-        })
-        .context("failed to deduce platform generation")
-        .map(|g| g.into())
+    // No built-in AMD root signing the CEK means the chain is not genuine.
+    Generation::try_from(chain).map(|g| g.into()).map_err(|_| {
+        Context::new(
+            "failed to deduce platform generation: no built-in AMD certificate signs the CEK",
+            Box::new(error::Error::Verification),
+        )
+    })
 }
 
 fn main() {
     let sevctl = Sevctl::from_args();
+    let output = sevctl.output.unwrap_or_default();
+    let options = config::Options {
+        config: sevctl.config,
+        kds_url: sevctl.kds_url,
+        ca_files: sevctl.ca_file,
+        retries: sevctl.retries,
+        max_backoff: sevctl.max_backoff,
+        retry_jitter: sevctl.retry_jitter,
+    };
+    let cache = cache::Cache::new(sevctl.cache_dir, sevctl.offline, options);
+    let platform = match firmware::Platform::new(sevctl.firmware) {
+        Ok(platform) => platform,
+        Err(err) => fail(err, sevctl.quiet, output),
+    };
     let status = match sevctl.cmd {
-        SevctlCmd::Export { full, destination } => export::cmd(full, destination),
-        SevctlCmd::Generate { cert, key } => generate::cmd(cert, key),
-        SevctlCmd::Ok { gen } => ok::cmd(gen, sevctl.quiet),
-        SevctlCmd::Provision { cert, key } => provision::cmd(cert, key),
-        SevctlCmd::Reset => reset::cmd(),
-        SevctlCmd::Rotate => rotate::cmd(),
-        SevctlCmd::Session { name, pdh, policy } => session::cmd(name, pdh, policy),
-        SevctlCmd::Show { cmd } => show::cmd(cmd),
-        SevctlCmd::Verify { sev, oca, ca } => verify::cmd(sevctl.quiet, sev, oca, ca),
+        SevctlCmd::Cache { cmd } => cache::cmd(cmd, &cache, &platform, output),
+        SevctlCmd::Decode { json, file } => {
+            decode::cmd(file, json || output == Output::Json, sevctl.quiet)
+        }
+        SevctlCmd::Export { full, destination } => {
+            export::cmd(full, destination, &platform, &cache, output)
+        }
+        SevctlCmd::Generate { cert, key } => generate::cmd(cert, key, output),
+        SevctlCmd::Measurement { cmd } => measurement::cmd(cmd, sevctl.quiet, output),
+        SevctlCmd::Ok {
+            gen,
+            format,
+            profile,
+            record,
+            rules,
+        } => ok::cmd(
+            gen,
+            format.unwrap_or(output),
+            sevctl.quiet,
+            profile,
+            record,
+            rules,
+            &platform,
+        ),
+        SevctlCmd::Provision { cert, key } => provision::cmd(&platform, cert, key, output),
+        SevctlCmd::Reset => reset::cmd(&platform, output),
+        SevctlCmd::Rotate => rotate::cmd(&platform, output),
+        SevctlCmd::Secret { cmd } => secret::cmd(cmd, output),
+        SevctlCmd::Policy { cmd } => policy::cmd(cmd, output),
+        SevctlCmd::Session { name, pdh, policy } => policy
+            .policy()
+            .and_then(|policy| session::cmd(name, pdh, policy, output)),
+        SevctlCmd::Show { cmd } => {
+            show::cmd(cmd, &platform, &probe::Host(&platform), &cache, output)
+        }
+        SevctlCmd::Snp { cmd } => snp::cmd(cmd, sevctl.quiet, &cache, &platform, output),
+        SevctlCmd::Vcek { cmd } => vcek::cmd(cmd, sevctl.quiet, &cache, &platform, output),
+        SevctlCmd::Verify { sev, oca, ca } => {
+            verify::cmd(sevctl.quiet, output, &platform, &cache, sev, oca, ca)
+        }
     };
 
     if let Err(err) = status {
-        if sevctl.quiet {
-            exit(1);
-        }
-        eprintln!("error: {}", err);
-        let mut err: &(dyn std::error::Error + 'static) = &err;
-        while let Some(cause) = err.source() {
-            eprintln!("caused by: {}", cause);
-            err = cause;
-        }
+        fail(err, sevctl.quiet, output);
+    }
+}
 
-        exit(1);
+/// Report `err` and exit with its exit code.
+fn fail(err: error::Context, quiet: bool, output: Output) -> ! {
+    let code = err.exit_code();
+    if quiet {
+        exit(code);
     }
+    if output == Output::Json {
+        eprintln!("{:#}", output::error(&err));
+        exit(code);
+    }
+    eprintln!("error: {}", err);
+    let mut err: &(dyn std::error::Error + 'static) = &err;
+    while let Some(cause) = err.source() {
+        eprintln!("caused by: {}", cause);
+        err = cause;
+    }
+
+    exit(code);
 }
 
 mod reset {
     use super::*;
 
-    pub fn cmd() -> Result<()> {
-        firmware()?
+    pub fn cmd(platform: &firmware::Platform, output: Output) -> Result<()> {
+        platform
+            .firmware()?
             .platform_reset()
-            .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
-            .context("error resetting platform")
+            .map_err(error::Error::from)
+            .context("error resetting platform")?;
+
+        output.json(&serde_json::json!({}))
     }
 }
 
 mod show {
     use super::*;
+    use crate::probe::Probe;
+    use ::sev::firmware::State;
+    use serde::Serialize;
+    use serde_json::{json, Value};
 
     #[derive(StructOpt)]
     pub enum Show {
+        #[structopt(about = "Show all available platform information")]
+        All,
+
         #[structopt(about = "Show the current platform flags")]
         Flags,
 
+        #[structopt(about = "Show the processor generation (Naples, Rome, Milan, ...)")]
+        Generation,
+
         #[structopt(about = "Show the current number of guests")]
         Guests,
 
+        #[structopt(about = "Show the SEV-SNP platform status")]
+        Snp,
+
         #[structopt(about = "Show the platform's firmware version")]
         Version,
     }
 
-    pub fn cmd(show: Show) -> Result<()> {
-        let status = platform_status()?;
+    pub fn cmd(
+        show: Show,
+        platform: &firmware::Platform,
+        probe: &dyn Probe,
+        cache: &cache::Cache,
+        output: Output,
+    ) -> Result<()> {
+        if output == Output::Json {
+            return output.json(&json(show, platform, probe, cache)?);
+        }
 
         match show {
-            Show::Version => println!("{}", status.build),
-            Show::Guests => println!("{}", status.guests),
+            Show::All => {
+                let status = platform_status(platform)?;
+                println!(
+                    "api version: {}.{}",
+                    status.build.version.major, status.build.version.minor
+                );
+                println!("build: {}", status.build.build);
+                println!("state: {}", state(&status));
+                println!("owner: {}", owner(&status));
+                println!("es: {}", yes_no(es(&status)));
+                println!("guests: {}", status.guests);
+
+                match chip_id(platform) {
+                    Ok(id) => println!("chip id: {}", id),
+                    Err(e) => println!("chip id: unavailable ({})", e),
+                }
+
+                match cpu::Generation::detect(probe) {
+                    Ok(generation) => println!("generation: {}", generation),
+                    Err(e) => println!("generation: unavailable ({})", e),
+                }
+                match cpu::microcode(probe) {
+                    Ok(microcode) => println!("microcode: {}", microcode),
+                    Err(e) => println!("microcode: unavailable ({})", e),
+                }
+
+                match snp::platform_status(platform) {
+                    Ok(snp) => print_snp(&snp, "snp "),
+                    Err(e) => println!("snp: unavailable ({})", e),
+                }
+            }
+            Show::Generation => println!("{}", generation(platform, probe, cache)?.1),
+            Show::Version => println!("{}", platform_status(platform)?.build),
+            Show::Guests => println!("{}", platform_status(platform)?.guests),
             Show::Flags => {
-                for f in [
-                    PlatformStatusFlags::OWNED,
-                    PlatformStatusFlags::ENCRYPTED_STATE,
-                ]
-                .iter()
-                {
-                    println!(
-                        "{}",
-                        match status.flags & *f {
-                            PlatformStatusFlags::ENCRYPTED_STATE => "es",
-                            PlatformStatusFlags::OWNED => "owned",
-                            _ => continue,
-                        }
-                    );
+                for f in flags(&platform_status(platform)?) {
+                    println!("{}", f);
                 }
             }
+            Show::Snp => print_snp(&snp::platform_status(platform)?, ""),
         }
 
         Ok(())
     }
+
+    pub fn json(
+        show: Show,
+        platform: &firmware::Platform,
+        probe: &dyn Probe,
+        cache: &cache::Cache,
+    ) -> Result<Value> {
+        Ok(match show {
+            Show::All => {
+                let status = platform_status(platform)?;
+                let (chip_id, chip_id_error) = result(chip_id(platform));
+                let (generation, generation_error) = result(cpu::Generation::detect(probe));
+                let (microcode, microcode_error) = result(cpu::microcode(probe));
+                let (snp, snp_error) = match snp::platform_status(platform) {
+                    Ok(snp) => (snp_json(&snp), Value::Null),
+                    Err(e) => (Value::Null, Value::from(e.to_string())),
+                };
+                json!({
+                    "version": status.build.to_string(),
+                    "api_major": status.build.version.major,
+                    "api_minor": status.build.version.minor,
+                    "build": status.build.build,
+                    "state": state(&status),
+                    "owner": owner(&status),
+                    "es": es(&status),
+                    "guests": status.guests,
+                    "flags": flags(&status),
+                    "chip_id": chip_id,
+                    "chip_id_error": chip_id_error,
+                    "generation": generation,
+                    "generation_error": generation_error,
+                    "microcode": microcode,
+                    "microcode_error": microcode_error,
+                    "snp": snp,
+                    "snp_error": snp_error,
+                })
+            }
+            Show::Generation => {
+                let (signature, generation, firmware) = generation(platform, probe, cache)?;
+                json!({
+                    "generation": generation,
+                    "family": signature.family,
+                    "model": signature.model,
+                    "stepping": signature.stepping,
+                    "firmware_generation": firmware,
+                })
+            }
+            Show::Version => json!({ "version": platform_status(platform)?.build.to_string() }),
+            Show::Guests => json!({ "guests": platform_status(platform)?.guests }),
+            Show::Flags => json!({ "flags": flags(&platform_status(platform)?) }),
+            Show::Snp => snp_json(&snp::platform_status(platform)?),
+        })
+    }
+
+    /// The processor generation according to CPUID, along with the
+    /// generation of the platform's SEV certificate chain, if its CEK is
+    /// already cached and from a generation that it identifies (Naples or
+    /// Rome). Nothing is downloaded.
+    fn generation(
+        platform: &firmware::Platform,
+        probe: &dyn Probe,
+        cache: &cache::Cache,
+    ) -> Result<(cpu::Signature, cpu::Generation, Option<cpu::Generation>)> {
+        use std::convert::TryFrom;
+
+        let signature = cpu::Signature::read(probe);
+        let generation = cpu::Generation::detect(probe)?;
+
+        let firmware = cached_chain(platform, cache)
+            .and_then(|chain| Generation::try_from(&chain).ok())
+            .map(cpu::Generation::from);
+
+        match firmware {
+            Some(firmware) if firmware != generation => Err(error::Context::new(
+                &format!(
+                    "CPUID reports a {} processor, but the SEV certificate chain is from {}",
+                    generation, firmware
+                ),
+                Box::new(error::Error::Verification),
+            )),
+            _ => Ok((signature, generation, firmware)),
+        }
+    }
+
+    /// A value and its error as a pair of JSON values, one of them null.
+    fn result<T: Serialize>(res: Result<T>) -> (Value, Value) {
+        match res {
+            Ok(v) => (json!(v), Value::Null),
+            Err(e) => (Value::Null, Value::from(e.to_string())),
+        }
+    }
+
+    fn state(status: &Status) -> &'static str {
+        match status.state {
+            State::Uninitialized => "UNINIT",
+            State::Initialized => "INIT",
+            State::Working => "WORKING",
+        }
+    }
+
+    fn owner(status: &Status) -> &'static str {
+        if status.flags.contains(PlatformStatusFlags::OWNED) {
+            "external"
+        } else {
+            "self"
+        }
+    }
+
+    fn es(status: &Status) -> bool {
+        status.flags.contains(PlatformStatusFlags::ENCRYPTED_STATE)
+    }
+
+    fn yes_no(b: bool) -> &'static str {
+        if b {
+            "yes"
+        } else {
+            "no"
+        }
+    }
+
+    fn flags(status: &Status) -> Vec<&'static str> {
+        let mut flags = vec![];
+
+        for f in [
+            PlatformStatusFlags::OWNED,
+            PlatformStatusFlags::ENCRYPTED_STATE,
+        ]
+        .iter()
+        {
+            flags.push(match status.flags & *f {
+                PlatformStatusFlags::ENCRYPTED_STATE => "es",
+                PlatformStatusFlags::OWNED => "owned",
+                _ => continue,
+            });
+        }
+
+        flags
+    }
+
+    fn print_snp(status: &snp::SnpStatus, pfx: &str) {
+        println!(
+            "{}version: {}.{}.{}",
+            pfx, status.api_major, status.api_minor, status.build
+        );
+        println!("{}state: {}", pfx, status.state);
+        println!("{}initialized: {}", pfx, yes_no(status.initialized()));
+        println!("{}rmp initialized: {}", pfx, yes_no(status.rmp_initialized));
+        println!("{}guests: {}", pfx, status.guests);
+        println!("{}reported tcb: {}", pfx, status.reported_tcb);
+        println!("{}current tcb: {}", pfx, status.current_tcb);
+    }
+
+    fn snp_json(status: &snp::SnpStatus) -> Value {
+        json!({
+            "version": format!("{}.{}.{}", status.api_major, status.api_minor, status.build),
+            "state": status.state.to_string(),
+            "initialized": status.initialized(),
+            "rmp_initialized": status.rmp_initialized,
+            "guests": status.guests,
+            "reported_tcb": status.reported_tcb,
+            "current_tcb": status.current_tcb,
+        })
+    }
 }
 
 mod export {
     use super::*;
     use std::io::Write;
 
-    pub fn cmd(full: bool, dest: PathBuf) -> Result<()> {
-        let chain = chain()?;
+    pub fn cmd(
+        full: bool,
+        dest: PathBuf,
+        platform: &firmware::Platform,
+        cache: &cache::Cache,
+        output: Output,
+    ) -> Result<()> {
+        let chain = chain(platform, cache)?;
 
         let mut out = std::io::Cursor::new(Vec::new());
 
@@ -418,33 +1130,39 @@ mod export {
                 .context("certificate chain encoding failed")?;
         }
 
-        let mut file = File::create(dest).context("unable to create output file")?;
+        let mut file = File::create(&dest).context("unable to create output file")?;
 
         file.write_all(&out.into_inner())
             .context("unable to write output file")?;
 
-        Ok(())
+        output.json(&serde_json::json!({
+            "destination": dest,
+            "full": full,
+        }))
     }
 }
 
 mod verify {
     use super::*;
+    use crate::output::Link;
     use colorful::*;
     use std::convert::TryInto;
     use std::fmt::Display;
 
     pub fn cmd(
         quiet: bool,
+        output: Output,
+        platform: &firmware::Platform,
+        cache: &cache::Cache,
         sev: Option<PathBuf>,
         oca: Option<PathBuf>,
         ca: Option<PathBuf>,
     ) -> Result<()> {
-        let mut schain = sev_chain(sev)?;
+        let mut schain = sev_chain(platform, cache, sev)?;
         let cchain = match ca {
             Some(ca) => ca_chain(ca)?,
             None => ca_chain_builtin(&schain)?,
         };
-        let mut err = false;
 
         if let Some(filename) = oca {
             let mut file = File::open(filename).context("unable to open OCA certificate file")?;
@@ -452,34 +1170,44 @@ mod verify {
             schain.oca = sev::Certificate::decode(&mut file, ()).context("unable to decode OCA")?;
         }
 
-        if !quiet {
+        let text = !quiet && output == Output::Text;
+
+        if text {
             println!("{}", schain.pdh);
         }
-        err |= status("", &schain.pek, &schain.pdh, quiet);
-        err |= status("   ", &schain.oca, &schain.pek, quiet);
-        err |= status("   ", &schain.cek, &schain.pek, quiet);
-        err |= status("      ", &cchain.ask, &schain.cek, quiet);
-        err |= status("         ", &cchain.ark, &cchain.ask, quiet);
-
-        if !quiet {
+        let links = vec![
+            status("", &schain.pek, &schain.pdh, text),
+            status("   ", &schain.oca, &schain.pek, text),
+            status("   ", &schain.cek, &schain.pek, text),
+            status("      ", &cchain.ask, &schain.cek, text),
+            status("         ", &cchain.ark, &cchain.ask, text),
+        ];
+        let err = links.iter().any(|l| !l.valid());
+
+        if text {
             println!("\n • = self signed, ⬑ = signs, •̷ = invalid self sign, ⬑̸ = invalid signs");
         }
+        output.json(&serde_json::json!({
+            "valid": !err,
+            "links": links,
+        }))?;
 
         if err as i32 == 0 {
             Ok(())
         } else {
             Err(error::Context::new(
                 "SEV/CA certificate verification failed",
-                Box::<Error>::new(ErrorKind::InvalidData.into()),
+                Box::new(error::Error::Verification),
             ))
         }
     }
 
-    fn status<'a, P, C>(pfx: &str, p: &'a P, c: &'a C, quiet: bool) -> bool
+    fn status<'a, P, C>(pfx: &str, p: &'a P, c: &'a C, print: bool) -> Link
     where
         P: Display,
         C: Display,
         &'a P: TryInto<Usage, Error = Error>,
+        &'a C: TryInto<Usage, Error = Error>,
         (&'a P, &'a P): Verifiable,
         (&'a P, &'a C): Verifiable,
     {
@@ -490,7 +1218,15 @@ mod verify {
             "⬑̸".red()
         };
 
-        !match p.try_into().unwrap() {
+        let usage = p.try_into().unwrap();
+        let mut link = Link {
+            signer: usage.to_string(),
+            subject: c.try_into().unwrap().to_string(),
+            signature_valid: sig_valid,
+            self_signature_valid: None,
+        };
+
+        match usage {
             Usage::OCA | Usage::ARK => {
                 let selfsig_valid = (p, p).verify().is_ok();
                 let slf = if selfsig_valid {
@@ -498,24 +1234,29 @@ mod verify {
                 } else {
                     "•̷".red()
                 };
-                if !quiet {
+                if print {
                     println!("{}{}{} {}", pfx, slf, lnk, p);
                 }
-                sig_valid && selfsig_valid
+                link.self_signature_valid = Some(selfsig_valid);
             }
 
             _ => {
-                if !quiet {
+                if print {
                     println!("{} {} {}", pfx, lnk, p);
                 }
-                sig_valid
             }
         }
+
+        link
     }
 
-    fn sev_chain(filename: Option<PathBuf>) -> Result<sev::Chain> {
+    fn sev_chain(
+        platform: &firmware::Platform,
+        cache: &cache::Cache,
+        filename: Option<PathBuf>,
+    ) -> Result<sev::Chain> {
         Ok(match filename {
-            None => chain()?,
+            None => chain(platform, cache)?,
             Some(f) => {
                 let mut file =
                     File::open(f).context("unable to open SEV certificate chain file")?;
@@ -534,43 +1275,52 @@ mod verify {
 mod generate {
     use super::*;
 
-    pub fn cmd(oca_path: PathBuf, key_path: PathBuf) -> Result<()> {
+    pub fn cmd(oca_path: PathBuf, key_path: PathBuf, output: Output) -> Result<()> {
         let (mut oca, prv) = sev::Certificate::generate(sev::Usage::OCA)
             .context("unable to generate OCA key pair")?;
         prv.sign(&mut oca).context("key signing failed")?;
 
         // Write the certificate
-        let mut crt = File::create(oca_path).context("unable to create certificate file")?;
+        let mut crt = File::create(&oca_path).context("unable to create certificate file")?;
         oca.encode(&mut crt, ())
             .context("unable to write certificate file")?;
 
         // Write the private key
-        let mut key = File::create(key_path).context("unable to create key file")?;
+        let mut key = File::create(&key_path).context("unable to create key file")?;
         prv.encode(&mut key, ())
             .context("unable to write key file")?;
 
-        Ok(())
+        output.json(&serde_json::json!({
+            "certificate": oca_path,
+            "key": key_path,
+        }))
     }
 }
 
 mod rotate {
     use super::*;
 
-    pub fn cmd() -> Result<()> {
-        firmware()?
+    pub fn cmd(platform: &firmware::Platform, output: Output) -> Result<()> {
+        platform
+            .firmware()?
             .pdh_generate()
-            .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
+            .map_err(error::Error::from)
             .context("unable to rotate PDH")?;
 
-        Ok(())
+        output.json(&serde_json::json!({}))
     }
 }
 
 mod provision {
     use super::*;
 
-    pub fn cmd(oca_path: PathBuf, prv_key_path: PathBuf) -> Result<()> {
-        let mut fw = firmware()?;
+    pub fn cmd(
+        platform: &firmware::Platform,
+        oca_path: PathBuf,
+        prv_key_path: PathBuf,
+        output: Output,
+    ) -> Result<()> {
+        let mut fw = platform.firmware()?;
         let cert = File::open(oca_path.clone())
             .context(format!("failed to open {}", oca_path.display()))
             .and_then(|mut f| {
@@ -586,15 +1336,200 @@ mod provision {
 
         let mut pek = fw
             .pek_csr()
-            .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
+            .map_err(error::Error::from)
             .context("cross signing request failed")?;
         prv_key
             .sign(&mut pek)
             .context("failed to sign PEK with OCA private key")?;
         fw.pek_cert_import(&pek, &cert)
-            .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
+            .map_err(error::Error::from)
             .context("failed to import the newly-signed PEK")?;
 
-        Ok(())
+        output.json(&serde_json::json!({}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firmware::{Backend, Platform};
+    use std::fs;
+
+    fn bytes(cert: &sev::Certificate) -> Vec<u8> {
+        let mut bytes = vec![];
+        cert.encode(&mut bytes, ()).unwrap();
+        bytes
+    }
+
+    fn owned(platform: &Platform) -> bool {
+        platform_status(platform)
+            .unwrap()
+            .flags
+            .contains(PlatformStatusFlags::OWNED)
+    }
+
+    #[test]
+    fn provisioning_flow() {
+        let dir = std::env::temp_dir().join(format!("sevctl-provision-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let platform = Platform::new(Some(Backend::Emulated)).unwrap();
+        assert!(!owned(&platform));
+
+        let (oca, key) = (dir.join("oca.cert"), dir.join("oca.key"));
+        generate::cmd(oca.clone(), key.clone(), Output::Text).unwrap();
+        provision::cmd(&platform, oca.clone(), key, Output::Text).unwrap();
+        assert!(owned(&platform));
+
+        // The emulated CEK is unknown to the KDS, so serve it from the cache.
+        let cek = platform.firmware().unwrap().pdh_cert_export().unwrap().cek;
+        let id = chip_id(&platform).unwrap();
+        let cached = dir.join("cache").join(cache::cek_key(&id).unwrap());
+        fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        fs::write(&cached, bytes(&cek)).unwrap();
+        let cache = cache::Cache::new(Some(dir.join("cache")), true, config::Options::default());
+
+        let dest = dir.join("chain.cert");
+        export::cmd(false, dest.clone(), &platform, &cache, Output::Text).unwrap();
+        let exported = sev::Chain::decode(&mut File::open(&dest).unwrap(), ()).unwrap();
+        let oca = sev::Certificate::decode(&mut File::open(&oca).unwrap(), ()).unwrap();
+        assert_eq!(bytes(&exported.oca), bytes(&oca));
+        (&exported.oca, &exported.pek).verify().unwrap();
+        (&exported.pek, &exported.pdh).verify().unwrap();
+
+        rotate::cmd(&platform, Output::Text).unwrap();
+        let rotated = chain(&platform, &cache).unwrap();
+        assert_ne!(bytes(&rotated.pdh), bytes(&exported.pdh));
+        (&rotated.pek, &rotated.pdh).verify().unwrap();
+
+        reset::cmd(&platform, Output::Text).unwrap();
+        assert!(!owned(&platform));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A Milan host with microcode patch level 0xa0011d1.
+    fn milan() -> probe::Profile {
+        let mut profile = probe::Profile::with_cpu(b"AuthenticAMD", 0x00a0_0f11);
+        profile.files.insert(
+            "/proc/cpuinfo".to_string(),
+            Ok("processor\t: 0\nvendor_id\t: AuthenticAMD\nmicrocode\t: 0xa0011d1\n".to_string()),
+        );
+        profile
+    }
+
+    #[test]
+    fn show_all_reports_platform() {
+        let dir = std::env::temp_dir().join(format!("sevctl-show-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let platform = Platform::new(Some(Backend::Emulated)).unwrap();
+        let cache = cache::Cache::new(Some(dir.clone()), true, config::Options::default());
+
+        let all = show::json(show::Show::All, &platform, &milan(), &cache);
+        let _ = fs::remove_dir_all(&dir);
+        let all = all.unwrap();
+        assert_eq!(
+            all,
+            serde_json::json!({
+                "version": "0.24.15",
+                "api_major": 0,
+                "api_minor": 24,
+                "build": 15,
+                "state": "INIT",
+                "owner": "self",
+                "es": true,
+                "guests": 0,
+                "flags": ["es"],
+                "chip_id": "5E".repeat(64),
+                "chip_id_error": null,
+                "generation": "Milan",
+                "generation_error": null,
+                "microcode": "0xa0011d1",
+                "microcode_error": null,
+                "snp": {
+                    "version": "1.55.21",
+                    "state": "INIT",
+                    "initialized": true,
+                    "rmp_initialized": true,
+                    "guests": 0,
+                    "reported_tcb": { "bootloader": 3, "tee": 0, "snp": 8, "microcode": 115 },
+                    "current_tcb": { "bootloader": 3, "tee": 0, "snp": 8, "microcode": 115 },
+                },
+                "snp_error": null,
+            })
+        );
+    }
+
+    #[test]
+    fn show_all_reports_what_is_unavailable() {
+        let platform = Platform::new(Some(Backend::Emulated)).unwrap();
+        let cache = cache::Cache::new(None, true, config::Options::default());
+
+        let all = show::json(
+            show::Show::All,
+            &platform,
+            &probe::Profile::default(),
+            &cache,
+        )
+        .unwrap();
+        assert!(all["generation"].is_null());
+        let error = all["generation_error"].as_str().unwrap();
+        assert!(error.starts_with("not an AMD processor"), "{}", error);
+        assert!(all["microcode"].is_null());
+        assert_eq!(all["microcode_error"], "unable to read /proc/cpuinfo");
+        assert_eq!(all["snp"]["version"], "1.55.21");
+    }
+
+    #[test]
+    fn show_generation_reads_cpuid() {
+        let platform = Platform::new(Some(Backend::Emulated)).unwrap();
+        let cache = cache::Cache::new(None, true, config::Options::default());
+
+        let generation = show::json(show::Show::Generation, &platform, &milan(), &cache).unwrap();
+        assert_eq!(
+            generation,
+            serde_json::json!({
+                "generation": "Milan",
+                "family": 0x19,
+                "model": 0x01,
+                "stepping": 0x1,
+                "firmware_generation": null,
+            })
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let max = Duration::from_secs(60);
+        let delays: Vec<u64> = (0..8).map(|n| backoff(n, None, max).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+
+        assert_eq!(backoff(100, None, max), max);
+        assert_eq!(backoff(3, None, Duration::from_secs(5)).as_secs(), 5);
+        assert_eq!(backoff(0, None, Duration::default()), Duration::default());
+    }
+
+    #[test]
+    fn retry_after_replaces_backoff_up_to_the_cap() {
+        let max = Duration::from_secs(60);
+        let asked = |secs| Some(Duration::from_secs(secs));
+
+        assert_eq!(backoff(0, asked(10), max).as_secs(), 10);
+        assert_eq!(backoff(5, asked(0), max).as_secs(), 0);
+        assert_eq!(backoff(0, asked(3600), max), max);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_bound() {
+        assert_eq!(jitter(Duration::default()), Duration::default());
+
+        let max = Duration::from_millis(5);
+        for _ in 0..100 {
+            assert!(jitter(max) <= max);
+        }
+        let max = Duration::from_secs(1);
+        assert!((0..100).any(|_| jitter(max) > Duration::default()));
     }
 }
@@ -0,0 +1,603 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offline computation of the SEV launch measurement, so that a guest owner
+//! can predict the value returned by LAUNCH_MEASURE before the guest boots.
+
+use crate::error::{self, Context, Contextual, Result};
+use crate::output::Output;
+use crate::policy::LaunchPolicy;
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::{sha256, Sha256};
+use openssl::sign::Signer;
+use serde_json::json;
+use structopt::StructOpt;
+
+/// GUIDs of the SEV hashes table that QEMU appends to the firmware when
+/// direct kernel boot is measured (`kernel-hashes=on`).
+const SEV_HASH_TABLE_HEADER_GUID: &str = "9438d606-4f22-4cc9-b479-a793d411fd21";
+const SEV_KERNEL_ENTRY_GUID: &str = "4de79437-abd2-427f-b835-d5b172d2045b";
+const SEV_INITRD_ENTRY_GUID: &str = "44baf731-3a2f-4bd7-9af1-41e29169781d";
+const SEV_CMDLINE_ENTRY_GUID: &str = "97d02dd8-bd20-4c94-aa78-e7714d36ab2a";
+
+const TIK_LEN: usize = 16;
+const MNONCE_LEN: usize = 16;
+const DIGEST_LEN: usize = 32;
+
+#[derive(StructOpt)]
+pub enum MeasurementCmd {
+    #[structopt(about = "Build the expected launch measurement of a SEV guest")]
+    Build(BuildArgs),
+
+    #[structopt(about = "Verify a launch measurement returned by QEMU")]
+    Verify(VerifyArgs),
+}
+
+/// Inputs shared by every command that recomputes a launch measurement.
+#[derive(StructOpt)]
+pub struct LaunchArgs {
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Path of the TIK file written by `sevctl session`"
+    )]
+    pub tik: PathBuf,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        required_unless = "launch-digest",
+        conflicts_with = "launch-digest",
+        help = "Path of the OVMF firmware binary"
+    )]
+    pub ovmf: Option<PathBuf>,
+
+    #[structopt(long, help = "Hex-encoded SHA-256 launch digest, instead of --ovmf")]
+    pub launch_digest: Option<String>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        requires = "ovmf",
+        help = "Kernel measured via the SEV hashes table"
+    )]
+    pub kernel: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        requires = "kernel",
+        help = "Initrd measured via the SEV hashes table"
+    )]
+    pub initrd: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        requires = "kernel",
+        help = "Kernel command line measured via the SEV hashes table"
+    )]
+    pub cmdline: Option<String>,
+
+    #[structopt(long, help = "Firmware API major version")]
+    pub api_major: u8,
+
+    #[structopt(long, help = "Firmware API minor version")]
+    pub api_minor: u8,
+
+    #[structopt(long, help = "Firmware build ID")]
+    pub build_id: u8,
+
+    #[structopt(
+        long,
+        help = "Launch policy as an integer or a comma-separated list of flags (e.g. nodbg,noks)"
+    )]
+    pub policy: LaunchPolicy,
+}
+
+#[derive(StructOpt)]
+pub struct BuildArgs {
+    #[structopt(flatten)]
+    pub launch: LaunchArgs,
+
+    #[structopt(long, help = "Base64-encoded measurement nonce (mnonce)")]
+    pub nonce: String,
+}
+
+#[derive(StructOpt)]
+pub struct VerifyArgs {
+    #[structopt(flatten)]
+    pub launch: LaunchArgs,
+
+    #[structopt(
+        long,
+        help = "Base64-encoded measurement || mnonce, as returned by `query-sev-launch-measure`"
+    )]
+    pub measurement: String,
+}
+
+pub fn cmd(cmd: MeasurementCmd, quiet: bool, output: Output) -> Result<()> {
+    match cmd {
+        MeasurementCmd::Build(args) => build(args, output),
+        MeasurementCmd::Verify(args) => verify(args, quiet, output),
+    }
+}
+
+fn build(args: BuildArgs, output: Output) -> Result<()> {
+    let mnonce = base64::decode(&args.nonce).context("measurement nonce is not valid base64")?;
+    if mnonce.len() != MNONCE_LEN {
+        return Err(Context::new(
+            &format!("measurement nonce must be {} bytes", MNONCE_LEN),
+            Box::<Error>::new(ErrorKind::InvalidInput.into()),
+        ));
+    }
+
+    let measurement = args.launch.measurement(&mnonce)?;
+
+    // Emit measurement || mnonce, matching the blob QEMU returns from
+    // `query-sev-launch-measure`.
+    let mut blob = measurement;
+    blob.extend_from_slice(&mnonce);
+    let blob = base64::encode(blob);
+
+    match output {
+        Output::Text => println!("{}", blob),
+        Output::Json => output.json(&json!({ "measurement": blob }))?,
+    }
+
+    Ok(())
+}
+
+fn verify(args: VerifyArgs, quiet: bool, output: Output) -> Result<()> {
+    let (measurement, mnonce) = split_measurement(&args.measurement)?;
+    let expected = args
+        .launch
+        .measurement(&mnonce)
+        .context("unable to recompute the expected launch measurement")?;
+
+    if !openssl::memcmp::eq(&expected, &measurement) {
+        return Err(Context::new(
+            "launch measurement does not match the expected value",
+            Box::new(error::Error::Verification),
+        ));
+    }
+
+    match output {
+        Output::Text if !quiet => println!("Launch measurement matches"),
+        Output::Text => (),
+        Output::Json => output.json(&json!({ "matches": true }))?,
+    }
+
+    Ok(())
+}
+
+impl LaunchArgs {
+    /// Recompute the launch measurement for these inputs and the given mnonce.
+    pub fn measurement(&self, mnonce: &[u8]) -> Result<Vec<u8>> {
+        let tik = read_tik(&self.tik)?;
+
+        let digest = match (&self.ovmf, &self.launch_digest) {
+            (Some(ovmf), _) => launch_digest(ovmf, &self.kernel, &self.initrd, &self.cmdline)?,
+            (None, Some(digest)) => parse_digest(digest)?,
+            (None, None) => {
+                return Err(Context::new(
+                    "either the OVMF binary or the launch digest is required",
+                    Box::<Error>::new(ErrorKind::InvalidInput.into()),
+                ))
+            }
+        };
+
+        launch_measurement(
+            &tik,
+            (self.api_major, self.api_minor, self.build_id),
+            self.policy.bits(),
+            &digest,
+            mnonce,
+        )
+    }
+}
+
+/// Split a base64 `query-sev-launch-measure` result into (measurement, mnonce).
+pub fn split_measurement(b64: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut blob = base64::decode(b64.trim())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+        .context("launch measurement is not valid base64")?;
+    if blob.len() != DIGEST_LEN + MNONCE_LEN {
+        return Err(Context::new(
+            &format!(
+                "launch measurement must be {} bytes, found {}",
+                DIGEST_LEN + MNONCE_LEN,
+                blob.len()
+            ),
+            Box::<Error>::new(ErrorKind::InvalidInput.into()),
+        ));
+    }
+
+    let mnonce = blob.split_off(DIGEST_LEN);
+    Ok((blob, mnonce))
+}
+
+fn parse_digest(digest: &str) -> Result<[u8; DIGEST_LEN]> {
+    let bytes = hex::decode(digest.trim())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+        .context("launch digest is not valid hex")?;
+    if bytes.len() != DIGEST_LEN {
+        return Err(Context::new(
+            &format!("launch digest must be {} bytes", DIGEST_LEN),
+            Box::<Error>::new(ErrorKind::InvalidInput.into()),
+        ));
+    }
+
+    let mut out = [0u8; DIGEST_LEN];
+    out.copy_from_slice(&bytes);
+    Ok(out)
+}
+
+/// Read a raw TIK as written by `sevctl session`.
+pub fn read_tik(path: &Path) -> Result<Vec<u8>> {
+    let tik = fs::read(path).context(format!("failed to read TIK file {}", path.display()))?;
+    if tik.len() != TIK_LEN {
+        return Err(Context::new(
+            &format!("TIK file must be {} bytes", TIK_LEN),
+            Box::<Error>::new(ErrorKind::InvalidData.into()),
+        ));
+    }
+
+    Ok(tik)
+}
+
+/// Compute GCTX.LD: the SHA-256 digest of every page encrypted with
+/// LAUNCH_UPDATE_DATA, i.e. the firmware followed by the optional hashes table.
+pub fn launch_digest(
+    ovmf: &Path,
+    kernel: &Option<PathBuf>,
+    initrd: &Option<PathBuf>,
+    cmdline: &Option<String>,
+) -> Result<[u8; DIGEST_LEN]> {
+    let firmware = fs::read(ovmf).context(format!("failed to read {}", ovmf.display()))?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(&firmware);
+
+    if let Some(kernel) = kernel {
+        hasher.update(&hashes_table(kernel, initrd, cmdline)?);
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Build the padded SEV hashes table describing the kernel, initrd and command line.
+fn hashes_table(
+    kernel: &Path,
+    initrd: &Option<PathBuf>,
+    cmdline: &Option<String>,
+) -> Result<Vec<u8>> {
+    let kernel = fs::read(kernel).context(format!("failed to read {}", kernel.display()))?;
+    let initrd = match initrd {
+        Some(path) => fs::read(path).context(format!("failed to read {}", path.display()))?,
+        None => vec![],
+    };
+
+    // The command line is measured including its NUL terminator.
+    let mut cmdline = cmdline.clone().unwrap_or_default().into_bytes();
+    cmdline.push(0);
+
+    let entries = [
+        (SEV_CMDLINE_ENTRY_GUID, sha256(&cmdline)),
+        (SEV_INITRD_ENTRY_GUID, sha256(&initrd)),
+        (SEV_KERNEL_ENTRY_GUID, sha256(&kernel)),
+    ];
+
+    // Each entry is GUID || u16 length || SHA-256 digest.
+    let entry_len = 16 + 2 + DIGEST_LEN;
+    let table_len = 16 + 2 + entries.len() * entry_len;
+
+    let mut table = Vec::with_capacity(table_len);
+    table.extend_from_slice(&guid_le(SEV_HASH_TABLE_HEADER_GUID)?);
+    table.extend_from_slice(&(table_len as u16).to_le_bytes());
+    for (guid, digest) in entries.iter() {
+        table.extend_from_slice(&guid_le(guid)?);
+        table.extend_from_slice(&(entry_len as u16).to_le_bytes());
+        table.extend_from_slice(digest);
+    }
+
+    // QEMU encrypts the table in whole 16-byte blocks.
+    table.resize((table_len + 15) & !15, 0);
+
+    Ok(table)
+}
+
+/// Compute the LAUNCH_MEASURE HMAC:
+/// HMAC-SHA256(TIK, 0x04 || API_MAJOR || API_MINOR || BUILD || POLICY || LD || MNONCE)
+pub fn launch_measurement(
+    tik: &[u8],
+    (api_major, api_minor, build_id): (u8, u8, u8),
+    policy: u32,
+    digest: &[u8],
+    mnonce: &[u8],
+) -> Result<Vec<u8>> {
+    let key = PKey::hmac(tik).context("failed to load TIK as HMAC key")?;
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).context("failed to create HMAC signer")?;
+
+    signer
+        .update(&[0x04, api_major, api_minor, build_id])
+        .and_then(|_| signer.update(&policy.to_le_bytes()))
+        .and_then(|_| signer.update(digest))
+        .and_then(|_| signer.update(mnonce))
+        .context("failed to compute launch measurement")?;
+
+    signer
+        .sign_to_vec()
+        .context("failed to compute launch measurement")
+}
+
+/// Convert a textual GUID into its little-endian (mixed-endian) binary form
+/// as used by EDK2 and the SEV firmware.
+pub fn guid_le(guid: &str) -> Result<[u8; 16]> {
+    let invalid = || {
+        Context::new(
+            &format!("invalid GUID {}", guid),
+            Box::<Error>::new(ErrorKind::InvalidInput.into()),
+        )
+    };
+
+    let fields: Vec<&str> = guid.split('-').collect();
+    let hex: String = fields.concat();
+    if fields.len() != 5
+        || [8, 4, 4, 4, 12]
+            .iter()
+            .zip(fields.iter())
+            .any(|(len, f)| f.len() != *len)
+        || !hex.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(invalid());
+    }
+
+    let mut bytes = [0u8; 16];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+
+    // The first three fields are stored little-endian.
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch directory holding `files`, removed when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str, files: &[(&str, &[u8])]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "sevctl-measurement-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            for (file, contents) in files {
+                fs::write(dir.join(file), contents).unwrap();
+            }
+
+            Scratch(dir)
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.0.join(file)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// The expected values below were computed independently of this module,
+    /// from the LAUNCH_MEASURE definition of the SEV API specification and
+    /// the `PaddedSevHashTable` layout of QEMU's target/i386/sev.c.
+    const KERNEL: &[u8] = b"kernel image";
+    const INITRD: &[u8] = b"initrd image";
+    const CMDLINE: &str = "console=ttyS0";
+
+    const TABLE: &str = concat!(
+        "06d63894224fc94cb479a793d411fd21a800",
+        "d82dd09720bd944caa78e7714d36ab2a3200",
+        "f18aae9b3c09e55bc3047ad361e2442d7c53372470b2958fb83293209a784f71",
+        "31f7ba442f3ad74b9af141e29169781d3200",
+        "44b5350b5a03f0551b4379be198d9ca981b505349192ef0bbd487857b2e712a0",
+        "3794e74dd2ab7f42b835d5b172d2045b3200",
+        "a8438c585bb5070930b9d66b141a05ef02bb7a326620ae09fc44f2d1f4e2a9a7",
+        "0000000000000000",
+    );
+
+    #[test]
+    fn guids_are_mixed_endian() {
+        // QEMU declares these with UUID_LE(), e.g. UUID_LE(0x9438d606,
+        // 0x4f22, 0x4cc9, 0xb4, 0x79, 0xa7, 0x93, 0xd4, 0x11, 0xfd, 0x21).
+        let guids = [
+            (
+                SEV_HASH_TABLE_HEADER_GUID,
+                "06d63894224fc94cb479a793d411fd21",
+            ),
+            (SEV_KERNEL_ENTRY_GUID, "3794e74dd2ab7f42b835d5b172d2045b"),
+            (SEV_INITRD_ENTRY_GUID, "31f7ba442f3ad74b9af141e29169781d"),
+            (SEV_CMDLINE_ENTRY_GUID, "d82dd09720bd944caa78e7714d36ab2a"),
+        ];
+
+        for (guid, bytes) in &guids {
+            assert_eq!(hex::encode(guid_le(guid).unwrap()), *bytes, "{}", guid);
+        }
+
+        for guid in &[
+            "9438d606-4f22-4cc9-b479",
+            "9438d6064f224cc9b479a793d411fd21",
+            "9438d606-4f22-4cc9-b479-a793d411fdzz",
+            "9438d606f-4f2-4cc9-b479-a793d411fd21",
+        ] {
+            assert!(guid_le(guid).is_err(), "{}", guid);
+        }
+    }
+
+    #[test]
+    fn hashes_table_is_padded_to_whole_blocks() {
+        let dir = Scratch::new("table", &[("kernel", KERNEL), ("initrd", INITRD)]);
+
+        let table = hashes_table(
+            &dir.path("kernel"),
+            &Some(dir.path("initrd")),
+            &Some(CMDLINE.to_string()),
+        )
+        .unwrap();
+
+        // 168 bytes of table, as recorded in its header, and 8 of padding.
+        assert_eq!(table.len(), 176);
+        assert_eq!(&table[16..18], &[168, 0]);
+        assert_eq!(hex::encode(&table), TABLE);
+    }
+
+    #[test]
+    fn hashes_table_measures_missing_initrd_and_cmdline_as_empty() {
+        let dir = Scratch::new("empty", &[("kernel", KERNEL)]);
+
+        let table = hashes_table(&dir.path("kernel"), &None, &None).unwrap();
+
+        assert_eq!(table.len(), 176);
+        // The command line is a lone NUL terminator.
+        assert_eq!(&table[36..68], &sha256(&[0]));
+        assert_eq!(&table[86..118], &sha256(&[]));
+    }
+
+    #[test]
+    fn launch_measurement_known_answer() {
+        let ovmf = [0x90; 4096];
+        let dir = Scratch::new(
+            "measure",
+            &[("ovmf", &ovmf), ("kernel", KERNEL), ("initrd", INITRD)],
+        );
+
+        let digest = launch_digest(&dir.path("ovmf"), &None, &None, &None).unwrap();
+        assert_eq!(
+            hex::encode(digest),
+            "a4c3775c02b3f3a5fa4f0c842e2a357deb1fb4374d8ee6e40702d24c332e4fc9"
+        );
+
+        let digest = launch_digest(
+            &dir.path("ovmf"),
+            &Some(dir.path("kernel")),
+            &Some(dir.path("initrd")),
+            &Some(CMDLINE.to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(digest),
+            "25f32dc013a67b8437a8a83b35972f29edb773914329ec5235589b504ab384af"
+        );
+
+        let tik: Vec<u8> = (0x00..0x10).collect();
+        let mnonce: Vec<u8> = (0x10..0x20).collect();
+        let measurement = launch_measurement(&tik, (0, 24, 15), 0x3, &digest, &mnonce).unwrap();
+        assert_eq!(
+            hex::encode(measurement),
+            "0d5f16a47e2d10a9d4d53703a1bdf4f5a9035b0ef3dd5d0dca4c388185f190ad"
+        );
+    }
+
+    /// `query-sev-launch-measure` output for the measurement above.
+    const MEASUREMENT: &str = "DV8WpH4tEKnU1TcDob309akDWw7z3V0Nykw4gYXxkK0QERITFBUWFxgZGhscHR4f";
+    const DIGEST: &str = "25f32dc013a67b8437a8a83b35972f29edb773914329ec5235589b504ab384af";
+
+    fn verify_args(dir: &Scratch, measurement: &str) -> VerifyArgs {
+        VerifyArgs {
+            launch: LaunchArgs {
+                tik: dir.path("tik"),
+                ovmf: None,
+                launch_digest: Some(DIGEST.to_string()),
+                kernel: None,
+                initrd: None,
+                cmdline: None,
+                api_major: 0,
+                api_minor: 24,
+                build_id: 15,
+                policy: "nodbg,noks".parse().unwrap(),
+            },
+            measurement: measurement.to_string(),
+        }
+    }
+
+    fn exit_code<T: std::fmt::Debug>(res: Result<T>) -> i32 {
+        res.unwrap_err().exit_code()
+    }
+
+    #[test]
+    fn measurement_splits_into_digest_and_mnonce() {
+        let (measurement, mnonce) = split_measurement(MEASUREMENT).unwrap();
+        assert_eq!(
+            hex::encode(measurement),
+            "0d5f16a47e2d10a9d4d53703a1bdf4f5a9035b0ef3dd5d0dca4c388185f190ad"
+        );
+        assert_eq!(mnonce, (0x10..0x20).collect::<Vec<u8>>());
+
+        let short = base64::encode(vec![0u8; DIGEST_LEN + MNONCE_LEN - 1]);
+        assert_eq!(
+            exit_code(split_measurement(&short)),
+            error::EXIT_INVALID_INPUT
+        );
+        assert_eq!(
+            exit_code(split_measurement("not base64!")),
+            error::EXIT_INVALID_INPUT
+        );
+    }
+
+    #[test]
+    fn digest_must_be_32_hex_bytes() {
+        assert_eq!(hex::encode(parse_digest(DIGEST).unwrap()), DIGEST);
+        assert_eq!(
+            exit_code(parse_digest(&DIGEST[2..])),
+            error::EXIT_INVALID_INPUT
+        );
+        assert_eq!(
+            exit_code(parse_digest(&format!("{}00", DIGEST))),
+            error::EXIT_INVALID_INPUT
+        );
+        assert_eq!(
+            exit_code(parse_digest(&DIGEST.replace('a', "g"))),
+            error::EXIT_INVALID_INPUT
+        );
+    }
+
+    #[test]
+    fn verify_compares_measurements() {
+        let tik: Vec<u8> = (0x00..0x10).collect();
+        let dir = Scratch::new("verify", &[("tik", &tik)]);
+
+        verify(verify_args(&dir, MEASUREMENT), true, Output::Text).unwrap();
+
+        // Flip a bit of the measurement, then of the mnonce.
+        for pos in &[0, DIGEST_LEN] {
+            let mut blob = base64::decode(MEASUREMENT).unwrap();
+            blob[*pos] ^= 1;
+            let res = verify(verify_args(&dir, &base64::encode(blob)), true, Output::Text);
+            assert_eq!(exit_code(res), error::EXIT_VERIFICATION);
+        }
+
+        let mut args = verify_args(&dir, MEASUREMENT);
+        args.launch.launch_digest = Some("0123".to_string());
+        assert_eq!(
+            exit_code(verify(args, true, Output::Text)),
+            error::EXIT_INVALID_INPUT
+        );
+    }
+}
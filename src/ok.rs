@@ -1,14 +1,13 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::*;
+use crate::firmware::Platform;
+use crate::output::Output;
+use crate::probe::{Host, Probe, Profile, Recorder};
+use crate::rules::{self, Rules};
 use colorful::*;
-use std::arch::x86_64;
+use serde::Serialize;
 use std::fmt;
-use std::fs;
-use std::mem::transmute;
-use std::mem::MaybeUninit;
-use std::os::unix::io::AsRawFd;
-use std::str::from_utf8;
 
 #[derive(StructOpt, PartialEq)]
 pub enum SevGeneration {
@@ -32,7 +31,7 @@ impl SevGeneration {
     }
 }
 
-type TestFn = dyn Fn() -> TestResult;
+type TestFn = dyn Fn(&dyn Probe) -> TestResult;
 
 // SEV generation-specific bitmasks.
 const SEV_MASK: usize = 1;
@@ -46,13 +45,17 @@ struct Test {
     sub: Vec<Test>,
 }
 
+#[derive(Serialize)]
 struct TestResult {
     name: &'static str,
+    #[serde(rename = "state")]
     stat: TestState,
+    #[serde(rename = "message")]
     mesg: Option<String>,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 enum TestState {
     Pass,
     Skip,
@@ -71,17 +74,18 @@ impl fmt::Display for TestState {
     }
 }
 
-fn collect_tests() -> Vec<Test> {
+fn collect_tests(rules: &Rules) -> Vec<Test> {
+    let min_guests = rules.min_encrypted_guests;
+    let min_firmware = rules.min_firmware;
+    let min_tcb = rules.min_snp_tcb;
+    let (min_soft, min_hard) = (rules.min_memlock_soft, rules.min_memlock_hard);
+
     let tests = vec![
         Test {
             name: "AMD CPU",
             gen_mask: SEV_MASK,
-            run: Box::new(|| {
-                let res = unsafe { x86_64::__cpuid(0x0000_0000) };
-                let name: [u8; 12] = unsafe { transmute([res.ebx, res.edx, res.ecx]) };
-                let name = from_utf8(&name[..]).unwrap_or("ERROR_FOUND");
-
-                let stat = if name == "AuthenticAMD" {
+            run: Box::new(|p: &dyn Probe| {
+                let stat = if cpu::vendor(p) == cpu::AMD {
                     TestState::Pass
                 } else {
                     TestState::Fail
@@ -97,11 +101,11 @@ fn collect_tests() -> Vec<Test> {
                 Test {
                     name: "Microcode support",
                     gen_mask: SEV_MASK,
-                    run: Box::new(|| {
+                    run: Box::new(|p: &dyn Probe| {
                         let cpu_name = {
                             let mut bytestr = Vec::with_capacity(48);
                             for cpuid in 0x8000_0002_u32..=0x8000_0004_u32 {
-                                let cpuid = unsafe { x86_64::__cpuid(cpuid) };
+                                let cpuid = p.cpuid(cpuid);
                                 let mut bytes: Vec<u8> =
                                     [cpuid.eax, cpuid.ebx, cpuid.ecx, cpuid.edx]
                                         .iter()
@@ -132,8 +136,8 @@ fn collect_tests() -> Vec<Test> {
                 Test {
                     name: "Secure Memory Encryption (SME)",
                     gen_mask: SEV_MASK,
-                    run: Box::new(|| {
-                        let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                    run: Box::new(|p: &dyn Probe| {
+                        let res = p.cpuid(0x8000_001f);
 
                         let stat = if (res.eax & 0x1) != 0 {
                             TestState::Pass
@@ -152,8 +156,8 @@ fn collect_tests() -> Vec<Test> {
                 Test {
                     name: "Secure Encrypted Virtualization (SEV)",
                     gen_mask: SEV_MASK,
-                    run: Box::new(|| {
-                        let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                    run: Box::new(|p: &dyn Probe| {
+                        let res = p.cpuid(0x8000_001f);
 
                         let stat = if (res.eax & 0x1 << 1) != 0 {
                             TestState::Pass
@@ -171,8 +175,8 @@ fn collect_tests() -> Vec<Test> {
                         Test {
                             name: "Encrypted State (SEV-ES)",
                             gen_mask: ES_MASK,
-                            run: Box::new(|| {
-                                let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                            run: Box::new(|p: &dyn Probe| {
+                                let res = p.cpuid(0x8000_001f);
 
                                 let stat = if (res.eax & 0x1 << 3) != 0 {
                                     TestState::Pass
@@ -186,13 +190,38 @@ fn collect_tests() -> Vec<Test> {
                                     mesg: None,
                                 }
                             }),
-                            sub: vec![],
+                            sub: vec![
+                                Test {
+                                    name: "SEV-ES enabled in KVM",
+                                    gen_mask: ES_MASK,
+                                    run: Box::new(es_enabled_in_kvm),
+                                    sub: vec![],
+                                },
+                                Test {
+                                    name: "ASIDs for SEV-ES guests",
+                                    gen_mask: ES_MASK,
+                                    run: Box::new(es_asids),
+                                    sub: vec![],
+                                },
+                                Test {
+                                    name: "SEV firmware API version for SEV-ES",
+                                    gen_mask: ES_MASK,
+                                    run: Box::new(es_api_version),
+                                    sub: vec![],
+                                },
+                                Test {
+                                    name: "SEV-ES supported by firmware",
+                                    gen_mask: ES_MASK,
+                                    run: Box::new(es_supported_by_firmware),
+                                    sub: vec![],
+                                },
+                            ],
                         },
                         Test {
                             name: "Secure Nested Paging (SEV-SNP)",
                             gen_mask: SNP_MASK,
-                            run: Box::new(|| {
-                                let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                            run: Box::new(|p: &dyn Probe| {
+                                let res = p.cpuid(0x8000_001f);
 
                                 let stat = if (res.eax & 0x1 << 4) != 0 {
                                     TestState::Pass
@@ -206,46 +235,88 @@ fn collect_tests() -> Vec<Test> {
                                     mesg: None,
                                 }
                             }),
-                            sub: vec![Test {
-                                name: "VM Permission Levels",
-                                gen_mask: SNP_MASK,
-                                run: Box::new(|| {
-                                    let res = unsafe { x86_64::__cpuid(0x8000_001f) };
-
-                                    let stat = if (res.eax & 0x1 << 5) != 0 {
-                                        TestState::Pass
-                                    } else {
-                                        TestState::Fail
-                                    };
-
-                                    TestResult {
-                                        name: "VM Permission Levels",
-                                        stat,
-                                        mesg: None,
-                                    }
-                                }),
-                                sub: vec![Test {
-                                    name: "Number of VMPLs",
+                            sub: vec![
+                                Test {
+                                    name: "VM Permission Levels",
                                     gen_mask: SNP_MASK,
-                                    run: Box::new(|| {
-                                        let res = unsafe { x86_64::__cpuid(0x8000_001f) };
-                                        let num_vmpls = (res.ebx & 0xF000) >> 12;
+                                    run: Box::new(|p: &dyn Probe| {
+                                        let res = p.cpuid(0x8000_001f);
+
+                                        let stat = if (res.eax & 0x1 << 5) != 0 {
+                                            TestState::Pass
+                                        } else {
+                                            TestState::Fail
+                                        };
 
                                         TestResult {
-                                            name: "Number of VMPLs",
-                                            stat: TestState::Pass,
-                                            mesg: Some(format!("{}", num_vmpls)),
+                                            name: "VM Permission Levels",
+                                            stat,
+                                            mesg: None,
                                         }
                                     }),
+                                    sub: vec![Test {
+                                        name: "Number of VMPLs",
+                                        gen_mask: SNP_MASK,
+                                        run: Box::new(|p: &dyn Probe| {
+                                            let res = p.cpuid(0x8000_001f);
+                                            let num_vmpls = (res.ebx & 0xF000) >> 12;
+
+                                            TestResult {
+                                                name: "Number of VMPLs",
+                                                stat: TestState::Pass,
+                                                mesg: Some(format!("{}", num_vmpls)),
+                                            }
+                                        }),
+                                        sub: vec![],
+                                    }],
+                                },
+                                Test {
+                                    name: "SEV-SNP enabled in SYSCFG MSR",
+                                    gen_mask: SNP_MASK,
+                                    run: Box::new(snp_syscfg),
+                                    sub: vec![Test {
+                                        name: "RMP table reserved",
+                                        gen_mask: SNP_MASK,
+                                        run: Box::new(rmp_reserved),
+                                        sub: vec![],
+                                    }],
+                                },
+                                Test {
+                                    name: "IOMMU enabled",
+                                    gen_mask: SNP_MASK,
+                                    run: Box::new(iommu_enabled),
+                                    sub: vec![],
+                                },
+                                Test {
+                                    name: "SEV-SNP supported by KVM",
+                                    gen_mask: SNP_MASK,
+                                    run: Box::new(snp_supported_by_kvm),
+                                    sub: vec![Test {
+                                        name: "SEV-SNP enabled in KVM",
+                                        gen_mask: SNP_MASK,
+                                        run: Box::new(snp_enabled_in_kvm),
+                                        sub: vec![],
+                                    }],
+                                },
+                                Test {
+                                    name: "SEV-SNP firmware initialized",
+                                    gen_mask: SNP_MASK,
+                                    run: Box::new(snp_initialized),
+                                    sub: vec![],
+                                },
+                                Test {
+                                    name: "SEV-SNP TCB version",
+                                    gen_mask: SNP_MASK,
+                                    run: Box::new(move |p: &dyn Probe| snp_tcb(p, min_tcb)),
                                     sub: vec![],
-                                }],
-                            }],
+                                },
+                            ],
                         },
                         Test {
                             name: "Physical address bit reduction",
                             gen_mask: SEV_MASK,
-                            run: Box::new(|| {
-                                let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                            run: Box::new(|p: &dyn Probe| {
+                                let res = p.cpuid(0x8000_001f);
                                 let field = res.ebx & 0b1111_1100_0000 >> 6;
 
                                 TestResult {
@@ -259,8 +330,8 @@ fn collect_tests() -> Vec<Test> {
                         Test {
                             name: "C-bit location",
                             gen_mask: SEV_MASK,
-                            run: Box::new(|| {
-                                let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                            run: Box::new(|p: &dyn Probe| {
+                                let res = p.cpuid(0x8000_001f);
                                 let field = res.ebx & 0b11_1111;
 
                                 TestResult {
@@ -274,14 +345,22 @@ fn collect_tests() -> Vec<Test> {
                         Test {
                             name: "Number of encrypted guests supported simultaneously",
                             gen_mask: SEV_MASK,
-                            run: Box::new(|| {
-                                let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                            run: Box::new(move |p: &dyn Probe| {
+                                let res = p.cpuid(0x8000_001f);
                                 let field = res.ecx;
 
+                                let (stat, mesg) = match min_guests {
+                                    Some(min) if field < min => (
+                                        TestState::Fail,
+                                        format!("{} (required: at least {})", field, min),
+                                    ),
+                                    _ => (TestState::Pass, format!("{}", field)),
+                                };
+
                                 TestResult {
                                     name: "Number of encrypted guests supported simultaneously",
-                                    stat: TestState::Pass,
-                                    mesg: Some(format!("{}", field)),
+                                    stat,
+                                    mesg: Some(mesg),
                                 }
                             }),
                             sub: vec![],
@@ -289,8 +368,8 @@ fn collect_tests() -> Vec<Test> {
                         Test {
                             name: "Minimum ASID value for SEV-enabled, SEV-ES disabled guest",
                             gen_mask: SEV_MASK,
-                            run: Box::new(|| {
-                                let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                            run: Box::new(|p: &dyn Probe| {
+                                let res = p.cpuid(0x8000_001f);
                                 let field = res.edx;
 
                                 TestResult {
@@ -320,13 +399,19 @@ fn collect_tests() -> Vec<Test> {
                             run: Box::new(dev_sev_w),
                             sub: vec![],
                         },
+                        Test {
+                            name: "SEV firmware version",
+                            gen_mask: SEV_MASK,
+                            run: Box::new(move |p: &dyn Probe| sev_firmware(p, min_firmware)),
+                            sub: vec![],
+                        },
                     ],
                 },
                 Test {
                     name: "Page flush MSR",
                     gen_mask: SEV_MASK,
-                    run: Box::new(|| {
-                        let res = unsafe { x86_64::__cpuid(0x8000_001f) };
+                    run: Box::new(|p: &dyn Probe| {
+                        let res = p.cpuid(0x8000_001f);
 
                         let stat = if (res.eax & 0x1 << 2) != 0 {
                             TestState::Pass
@@ -353,7 +438,7 @@ fn collect_tests() -> Vec<Test> {
         Test {
             name: "memlock limit",
             gen_mask: SEV_MASK,
-            run: Box::new(memlock_rlimit),
+            run: Box::new(move |p: &dyn Probe| memlock_rlimit(p, min_soft, min_hard)),
             sub: vec![],
         },
     ];
@@ -363,56 +448,146 @@ fn collect_tests() -> Vec<Test> {
 
 const INDENT: usize = 2;
 
-pub fn cmd(gen: Option<SevGeneration>, quiet: bool) -> Result<()> {
-    let tests = collect_tests();
+/// The outcome of a test along with the outcomes of its sub-tests.
+#[derive(Serialize)]
+struct TestReport {
+    #[serde(flatten)]
+    result: TestResult,
+    children: Vec<TestReport>,
+}
+
+#[derive(Serialize)]
+struct OkReport {
+    passed: bool,
+    tests: Vec<TestReport>,
+}
+
+pub fn cmd(
+    gen: Option<SevGeneration>,
+    format: Output,
+    quiet: bool,
+    profile: Option<PathBuf>,
+    record: Option<PathBuf>,
+    rules: Option<PathBuf>,
+    platform: &Platform,
+) -> Result<()> {
+    let probe: Box<dyn Probe> = match profile {
+        Some(path) => Box::new(Profile::load(&path)?),
+        None => Box::new(Host(platform)),
+    };
+    let rules = match rules {
+        Some(path) => Rules::load(&path)?,
+        None => Rules::default(),
+    };
+
+    match record {
+        Some(path) => {
+            // Make every probe up front so that the profile also covers the
+            // tests this host fails or skips, not just the ones it reaches.
+            let recorder = Recorder::new(&*probe);
+            let _ = current_gen(&recorder);
+            probe_all(&collect_tests(&rules), &recorder);
+
+            let res = probe_host(gen, format, quiet, &rules, &recorder);
+            recorder.into_profile().save(&path)?;
+            res
+        }
+        None => probe_host(gen, format, quiet, &rules, &*probe),
+    }
+}
+
+fn probe_host(
+    gen: Option<SevGeneration>,
+    format: Output,
+    quiet: bool,
+    rules: &Rules,
+    probe: &dyn Probe,
+) -> Result<()> {
+    let tests = collect_tests(rules);
 
     let mask = match gen {
         Some(g) => g.to_mask(),
-        None => current_gen().unwrap_or(SevGeneration::Snp).to_mask(),
+        None => current_gen(probe).unwrap_or(SevGeneration::Snp).to_mask(),
     };
 
-    if run_test(&tests, 0, quiet, mask) {
+    let (reports, passed) = run_test(&tests, mask, probe);
+
+    if !quiet {
+        match format {
+            Output::Text => emit_reports(&reports, 0),
+            Output::Json => format.json(&OkReport {
+                passed,
+                tests: reports,
+            })?,
+        }
+    }
+
+    if passed {
         Ok(())
     } else {
         Err(error::Context::new(
             "One or more tests in sevctl-ok reported a failure",
-            Box::<Error>::new(ErrorKind::InvalidData.into()),
+            Box::new(error::Error::Verification),
         ))
     }
 }
 
-fn run_test(tests: &[Test], level: usize, quiet: bool, mask: usize) -> bool {
+fn run_test(tests: &[Test], mask: usize, probe: &dyn Probe) -> (Vec<TestReport>, bool) {
+    let mut reports = Vec::with_capacity(tests.len());
     let mut passed = true;
 
     for t in tests {
         // Skip tests that aren't included in the specified generation.
         if (t.gen_mask & mask) != t.gen_mask {
-            test_gen_not_included(t, level, quiet);
+            reports.push(skip_report(t));
             continue;
         }
 
-        let res = (t.run)();
-        emit_result(&res, level, quiet);
-        match res.stat {
+        let res = (t.run)(probe);
+        let children = match res.stat {
             TestState::Pass => {
-                if !run_test(&t.sub, level + INDENT, quiet, mask) {
-                    passed = false;
-                }
+                let (children, sub_passed) = run_test(&t.sub, mask, probe);
+                passed &= sub_passed;
+                children
             }
             TestState::Fail => {
                 passed = false;
-                emit_skip(&t.sub, level + INDENT, quiet);
+                t.sub.iter().map(skip_report).collect()
             }
             // Skipped tests are marked as skip before recursing. They are just emitted and not actually processed.
             TestState::Skip => unreachable!(),
-        }
+        };
+
+        reports.push(TestReport {
+            result: res,
+            children,
+        });
     }
 
-    passed
+    (reports, passed)
 }
 
-fn emit_result(res: &TestResult, level: usize, quiet: bool) {
-    if !quiet {
+fn probe_all(tests: &[Test], probe: &dyn Probe) {
+    for t in tests {
+        (t.run)(probe);
+        probe_all(&t.sub, probe);
+    }
+}
+
+fn skip_report(test: &Test) -> TestReport {
+    TestReport {
+        result: TestResult {
+            name: test.name,
+            stat: TestState::Skip,
+            mesg: None,
+        },
+        children: test.sub.iter().map(skip_report).collect(),
+    }
+}
+
+fn emit_reports(reports: &[TestReport], level: usize) {
+    for r in reports {
+        let res = &r.result;
         let msg = match &res.mesg {
             Some(m) => format!(": {}", m),
             None => "".to_string(),
@@ -424,52 +599,13 @@ fn emit_result(res: &TestResult, level: usize, quiet: bool) {
             res.name,
             msg,
             width = level
-        )
-    }
-}
-
-fn test_gen_not_included(test: &Test, level: usize, quiet: bool) {
-    if !quiet {
-        let tr_skip = TestResult {
-            name: test.name,
-            stat: TestState::Skip,
-            mesg: None,
-        };
-
-        println!(
-            "[ {:^4} ] {:width$}- {}",
-            format!("{}", tr_skip.stat),
-            "",
-            tr_skip.name,
-            width = level
         );
-        emit_skip(&test.sub, level + INDENT, quiet);
-    }
-}
-
-fn emit_skip(tests: &[Test], level: usize, quiet: bool) {
-    if !quiet {
-        for t in tests {
-            let tr_skip = TestResult {
-                name: t.name,
-                stat: TestState::Skip,
-                mesg: None,
-            };
-
-            println!(
-                "[ {:^4} ] {:width$}- {}",
-                format!("{}", tr_skip.stat),
-                "",
-                tr_skip.name,
-                width = level
-            );
-            emit_skip(&t.sub, level + INDENT, quiet);
-        }
+        emit_reports(&r.children, level + INDENT);
     }
 }
 
-fn dev_sev_r() -> TestResult {
-    let (stat, mesg) = match dev_sev_rw(fs::OpenOptions::new().read(true)) {
+fn dev_sev_r(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.open("/dev/sev", false) {
         Ok(_) => (TestState::Pass, "/dev/sev readable".to_string()),
         Err(e) => (TestState::Fail, format!("/dev/sev not readable: {}", e)),
     };
@@ -481,8 +617,8 @@ fn dev_sev_r() -> TestResult {
     }
 }
 
-fn dev_sev_w() -> TestResult {
-    let (stat, mesg) = match dev_sev_rw(fs::OpenOptions::new().write(true)) {
+fn dev_sev_w(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.open("/dev/sev", true) {
         Ok(_) => (TestState::Pass, "/dev/sev writable".to_string()),
         Err(e) => (TestState::Fail, format!("/dev/sev not writable: {}", e)),
     };
@@ -494,171 +630,476 @@ fn dev_sev_w() -> TestResult {
     }
 }
 
-fn dev_sev_rw(file: &mut fs::OpenOptions) -> Result<()> {
-    let path = "/dev/sev";
+fn has_kvm_support(probe: &dyn Probe) -> TestResult {
+    let path = "/dev/kvm";
+
+    let (stat, mesg) = match probe.kvm_api_version() {
+        Ok(api_version) => (TestState::Pass, format!("API version: {}", api_version)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            (TestState::Fail, format!("Error reading {}: ({})", path, e))
+        }
+        Err(_) => (
+            TestState::Fail,
+            "Error - accessing KVM device node failed".to_string(),
+        ),
+    };
 
-    match file.open(path) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(error::Context::new(&e.to_string(), Box::new(e))),
+    TestResult {
+        name: "KVM supported",
+        stat,
+        mesg: Some(mesg),
     }
 }
 
-fn has_kvm_support() -> TestResult {
-    let path = "/dev/kvm";
+fn sev_enabled_in_kvm(probe: &dyn Probe) -> TestResult {
+    let path_loc = "/sys/module/kvm_amd/parameters/sev";
 
-    let (stat, mesg) = match File::open(path) {
-        Ok(kvm) => {
-            let api_version = unsafe { libc::ioctl(kvm.as_raw_fd(), 0xAE00, 0) };
-            if api_version < 0 {
+    let (stat, mesg) = match probe.read(path_loc) {
+        Ok(result) => {
+            if result.trim() == "1" || result.trim() == "Y" {
+                (TestState::Pass, "enabled".to_string())
+            } else {
                 (
                     TestState::Fail,
-                    "Error - accessing KVM device node failed".to_string(),
+                    format!("Error - contents read from {}: {}", path_loc, result.trim()),
                 )
-            } else {
-                (TestState::Pass, format!("API version: {}", api_version))
             }
         }
-        Err(e) => (TestState::Fail, format!("Error reading {}: ({})", path, e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => (
+            TestState::Fail,
+            format!("Error - {} does not exist", path_loc),
+        ),
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - (unable to read {}): {}", path_loc, e,),
+        ),
     };
 
     TestResult {
-        name: "KVM supported",
+        name: "SEV enabled in KVM",
         stat,
         mesg: Some(mesg),
     }
 }
 
-fn sev_enabled_in_kvm() -> TestResult {
-    let path_loc = "/sys/module/kvm_amd/parameters/sev";
-    let path = std::path::Path::new(path_loc);
+/// A resource limit as `ulimit` prints it.
+fn rlimit(limit: u64) -> String {
+    match limit {
+        libc::RLIM_INFINITY => "unlimited".to_string(),
+        limit => limit.to_string(),
+    }
+}
 
-    let (stat, mesg) = if path.exists() {
-        match std::fs::read_to_string(path_loc) {
-            Ok(result) => {
-                if result.trim() == "1" || result.trim() == "Y" {
-                    (TestState::Pass, "enabled".to_string())
-                } else {
-                    (
-                        TestState::Fail,
-                        format!("Error - contents read from {}: {}", path_loc, result.trim()),
-                    )
-                }
+fn memlock_rlimit(probe: &dyn Probe, min_soft: Option<u64>, min_hard: Option<u64>) -> TestResult {
+    let (stat, mesg) = match probe.memlock() {
+        Ok(r) => {
+            let limits = format!("Soft: {} | Hard: {}", rlimit(r.soft), rlimit(r.hard));
+            let mut required = vec![];
+            if let Some(min) = min_soft.filter(|min| r.soft < *min) {
+                required.push(format!("soft at least {}", rlimit(min)));
             }
-            Err(e) => (
+            if let Some(min) = min_hard.filter(|min| r.hard < *min) {
+                required.push(format!("hard at least {}", rlimit(min)));
+            }
+
+            if required.is_empty() {
+                (TestState::Pass, limits)
+            } else {
+                (
+                    TestState::Fail,
+                    format!("{} (required: {})", limits, required.join(", ")),
+                )
+            }
+        }
+        Err(_) => (
+            TestState::Fail,
+            "Unable to retrieve memlock resource limits".to_string(),
+        ),
+    };
+
+    TestResult {
+        name: "Memlock resource limit",
+        stat,
+        mesg: Some(mesg),
+    }
+}
+
+fn sev_firmware(probe: &dyn Probe, min: Option<snp::FirmwareVersion>) -> TestResult {
+    let (stat, mesg) = match probe.sev_status().map(|s| s.version) {
+        Ok(version) => match min {
+            Some(min) if version < min => (
                 TestState::Fail,
-                format!("Error - (unable to read {}): {}", path_loc, e,),
+                format!("{} (required: at least {})", version, min),
             ),
-        }
-    } else {
-        (
+            _ => (TestState::Pass, format!("{}", version)),
+        },
+        Err(e) => (
             TestState::Fail,
-            format!("Error - {} does not exist", path_loc),
-        )
+            format!("Error - unable to query the SEV firmware: {}", e),
+        ),
     };
 
     TestResult {
-        name: "SEV enabled in KVM",
+        name: "SEV firmware version",
         stat,
         mesg: Some(mesg),
     }
 }
 
-fn memlock_rlimit() -> TestResult {
-    let mut rlimit = MaybeUninit::uninit();
-    let res = unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, rlimit.as_mut_ptr()) };
+fn snp_tcb(probe: &dyn Probe, min: Option<snp::TcbVersion>) -> TestResult {
+    let (stat, mesg) = match probe.snp_status().map(|s| s.current_tcb) {
+        Ok(tcb) => match min {
+            Some(min) if !rules::tcb_meets(&tcb, &min) => (
+                TestState::Fail,
+                format!("{} (required: at least {})", tcb, min),
+            ),
+            _ => (TestState::Pass, format!("{}", tcb)),
+        },
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - unable to query the SEV-SNP firmware: {}", e),
+        ),
+    };
 
-    let (stat, mesg) = if res == 0 {
-        let r = unsafe { rlimit.assume_init() };
+    TestResult {
+        name: "SEV-SNP TCB version",
+        stat,
+        mesg: Some(mesg),
+    }
+}
 
-        (
-            TestState::Pass,
-            format!("Soft: {} | Hard: {}", r.rlim_cur, r.rlim_max),
-        )
+/// The SEV API version that introduced SEV-ES.
+const ES_MIN_API: (u8, u8) = (0, 17);
+
+const KVM_SEV_ES: &str = "/sys/module/kvm_amd/parameters/sev_es";
+
+fn es_enabled_in_kvm(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.read(KVM_SEV_ES) {
+        Ok(result) if result.trim() == "1" || result.trim() == "Y" => {
+            (TestState::Pass, "enabled".to_string())
+        }
+        Ok(result) => (
+            TestState::Fail,
+            format!(
+                "Error - contents read from {}: {}; load kvm_amd with sev_es=1, and check dmesg if that fails",
+                KVM_SEV_ES,
+                result.trim()
+            ),
+        ),
+        Err(e) if e.kind() == ErrorKind::NotFound => (
+            TestState::Fail,
+            format!(
+                "Error - {} does not exist; the host kernel lacks SEV-ES support or kvm_amd isn't loaded",
+                KVM_SEV_ES
+            ),
+        ),
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - (unable to read {}): {}", KVM_SEV_ES, e),
+        ),
+    };
+
+    TestResult {
+        name: "SEV-ES enabled in KVM",
+        stat,
+        mesg: Some(mesg),
+    }
+}
+
+/// ASIDs below the minimum SEV ASID are reserved for SEV-ES guests.
+fn es_asids(probe: &dyn Probe) -> TestResult {
+    let res = probe.cpuid(0x8000_001f);
+    let (max_asid, min_sev_asid) = (res.ecx, res.edx);
+    let es = min_sev_asid.saturating_sub(1).min(max_asid);
+    let sev = (max_asid + 1).saturating_sub(min_sev_asid.max(1));
+
+    let (stat, mesg) = if es > 0 {
+        (TestState::Pass, format!("SEV-ES: {} | SEV: {}", es, sev))
     } else {
         (
             TestState::Fail,
-            "Unable to retrieve memlock resource limits".to_string(),
+            format!(
+                "Error - no ASIDs are reserved for SEV-ES guests (SEV: {}); raise the SEV-ES ASID space limit in the BIOS setup",
+                sev
+            ),
         )
     };
 
     TestResult {
-        name: "Memlock resource limit",
+        name: "ASIDs for SEV-ES guests",
         stat,
         mesg: Some(mesg),
     }
 }
 
-const SEV_CPU_IDS: [usize; 3] = [7551, 7451, 7401];
-const ES_CPU_IDS: [usize; 2] = [7402, 7742];
-const SNP_CPU_IDS: [usize; 3] = [7713, 7763, 7413];
-
-fn current_gen() -> Result<SevGeneration> {
-    let mut bytestr = Vec::with_capacity(48);
-    let cpu_name = {
-        for cpuid in 0x8000_0002_u32..=0x8000_0004_u32 {
-            let cpuid = unsafe { x86_64::__cpuid(cpuid) };
-            let mut bytes: Vec<u8> = [cpuid.eax, cpuid.ebx, cpuid.ecx, cpuid.edx]
-                .iter()
-                .flat_map(|r| r.to_le_bytes().to_vec())
-                .collect();
-            bytestr.append(&mut bytes);
+fn es_api_version(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.sev_status().map(|s| s.version) {
+        Ok(v) if (v.major, v.minor) >= ES_MIN_API => {
+            (TestState::Pass, format!("{}.{}", v.major, v.minor))
         }
+        Ok(v) => (
+            TestState::Fail,
+            format!(
+                "Error - SEV API {}.{} predates SEV-ES (required: at least {}.{}); update the SEV firmware",
+                v.major, v.minor, ES_MIN_API.0, ES_MIN_API.1
+            ),
+        ),
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - unable to query the SEV firmware: {}", e),
+        ),
+    };
 
-        String::from_utf8(bytestr)
-            .unwrap_or_else(|_| "ERROR_FOUND".to_string())
-            .trim()
-            .to_string()
+    TestResult {
+        name: "SEV firmware API version for SEV-ES",
+        stat,
+        mesg: Some(mesg),
+    }
+}
+
+fn es_supported_by_firmware(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.sev_status() {
+        Ok(status) if status.encrypted_state => (TestState::Pass, "supported".to_string()),
+        Ok(_) => (
+            TestState::Fail,
+            "Error - the SEV firmware doesn't report the ENCRYPTED_STATE flag; enable SEV-ES in the BIOS setup"
+                .to_string(),
+        ),
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - unable to query the SEV firmware: {}", e),
+        ),
     };
 
-    let name = cpu_name.to_uppercase();
-    let id = get_id(name).context("couldn't convert CPU name to string")?;
+    TestResult {
+        name: "SEV-ES supported by firmware",
+        stat,
+        mesg: Some(mesg),
+    }
+}
 
-    for sevid in &SEV_CPU_IDS {
-        if id == *sevid {
-            return Ok(SevGeneration::Sev);
-        }
+const MSR_SYSCFG: u32 = 0xc001_0010;
+const MSR_RMP_BASE: u32 = 0xc001_0132;
+const MSR_RMP_END: u32 = 0xc001_0133;
+
+/// The SNPEn bit of SYSCFG.
+const SYSCFG_SNP_EN: u64 = 1 << 24;
+
+const KVM_SEV_SNP: &str = "/sys/module/kvm_amd/parameters/sev_snp";
+
+/// Reading MSRs requires root and the msr module.
+fn msr_error(msr: u32, e: &std::io::Error) -> String {
+    let hint = match e.kind() {
+        ErrorKind::NotFound => "load the msr module (modprobe msr)",
+        ErrorKind::PermissionDenied => "run as root",
+        _ => "check that /dev/cpu/0/msr is readable",
+    };
+
+    format!("Error - unable to read MSR {:#x} ({}): {}", msr, hint, e)
+}
+
+fn snp_syscfg(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.msr(MSR_SYSCFG) {
+        Ok(syscfg) if syscfg & SYSCFG_SNP_EN != 0 => (TestState::Pass, "enabled".to_string()),
+        Ok(_) => (
+            TestState::Fail,
+            "Error - SEV-SNP is disabled by the BIOS; enable SEV-SNP and SNP memory coverage in the BIOS setup"
+                .to_string(),
+        ),
+        Err(e) => (TestState::Fail, msr_error(MSR_SYSCFG, &e)),
+    };
+
+    TestResult {
+        name: "SEV-SNP enabled in SYSCFG MSR",
+        stat,
+        mesg: Some(mesg),
     }
+}
 
-    for esid in &ES_CPU_IDS {
-        if id == *esid {
-            return Ok(SevGeneration::Es);
-        }
+fn rmp_reserved(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match rmp_range(probe) {
+        Ok((base, end)) => match probe.read("/proc/iomem") {
+            Ok(iomem) => match iomem_reserves(&iomem, base, end) {
+                Some(true) => (TestState::Pass, format!("{:#x}-{:#x}", base, end)),
+                Some(false) => (
+                    TestState::Fail,
+                    format!(
+                        "Error - the RMP table at {:#x}-{:#x} is not reserved in /proc/iomem; update the BIOS",
+                        base, end
+                    ),
+                ),
+                None => (
+                    TestState::Fail,
+                    "Error - /proc/iomem hides addresses; run as root".to_string(),
+                ),
+            },
+            Err(e) => (
+                TestState::Fail,
+                format!("Error - (unable to read /proc/iomem): {}", e),
+            ),
+        },
+        Err(mesg) => (TestState::Fail, mesg),
+    };
+
+    TestResult {
+        name: "RMP table reserved",
+        stat,
+        mesg: Some(mesg),
     }
+}
 
-    for snpid in &SNP_CPU_IDS {
-        if id == *snpid {
-            return Ok(SevGeneration::Snp);
-        }
+/// The first and last byte of the RMP table, as set up by the BIOS.
+fn rmp_range(probe: &dyn Probe) -> std::result::Result<(u64, u64), String> {
+    let base = probe
+        .msr(MSR_RMP_BASE)
+        .map_err(|e| msr_error(MSR_RMP_BASE, &e))?;
+    let end = probe
+        .msr(MSR_RMP_END)
+        .map_err(|e| msr_error(MSR_RMP_END, &e))?;
+
+    if base == 0 || end <= base {
+        return Err(
+            "Error - the BIOS didn't set up an RMP table; enable SNP memory coverage in the BIOS setup"
+                .to_string(),
+        );
     }
 
-    Err(error::Context::new(
-        "unable to find AMD processor generation",
-        Box::<Error>::new(ErrorKind::InvalidData.into()),
-    ))
+    Ok((base, end))
 }
 
-fn get_id(cpuidstr: String) -> Result<usize> {
-    let arr = cpuidstr.split(' ');
+/// Whether a reserved region of `iomem` covers `base` to `end`, or `None`
+/// if the addresses are hidden from unprivileged users.
+fn iomem_reserves(iomem: &str, base: u64, end: u64) -> Option<bool> {
+    let mut hidden = true;
 
-    for s in arr {
-        let s = s.to_string();
-        let mut numeric = true;
-        for c in s.chars() {
-            if !c.is_numeric() {
-                numeric = false;
-            }
+    for line in iomem.lines() {
+        let pos = match line.find(" : ") {
+            Some(pos) => pos,
+            None => continue,
+        };
+        let (range, name) = (line[..pos].trim(), &line[pos + 3..]);
+        let dash = match range.find('-') {
+            Some(dash) => dash,
+            None => continue,
+        };
+        let start = u64::from_str_radix(&range[..dash], 16).unwrap_or(0);
+        let last = u64::from_str_radix(&range[dash + 1..], 16).unwrap_or(0);
+
+        hidden &= start == 0 && last == 0;
+        let reserved = name.contains("Reserved") || name.contains("RMP");
+        if reserved && start <= base && last >= end {
+            return Some(true);
         }
-        if !numeric {
-            continue;
+    }
+
+    if hidden {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+fn iommu_enabled(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.list("/sys/class/iommu") {
+        Ok(iommus) if !iommus.is_empty() => (TestState::Pass, iommus.join(", ")),
+        Ok(_) => (
+            TestState::Fail,
+            "Error - no IOMMU is enabled; enable the IOMMU in the BIOS setup and don't boot with amd_iommu=off"
+                .to_string(),
+        ),
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - (unable to read /sys/class/iommu): {}", e),
+        ),
+    };
+
+    TestResult {
+        name: "IOMMU enabled",
+        stat,
+        mesg: Some(mesg),
+    }
+}
+
+fn snp_supported_by_kvm(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.read(KVM_SEV_SNP) {
+        Ok(_) => (TestState::Pass, "supported".to_string()),
+        Err(e) if e.kind() == ErrorKind::NotFound => (
+            TestState::Fail,
+            format!(
+                "Error - {} does not exist; the host kernel lacks SEV-SNP support or kvm_amd isn't loaded",
+                KVM_SEV_SNP
+            ),
+        ),
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - (unable to read {}): {}", KVM_SEV_SNP, e),
+        ),
+    };
+
+    TestResult {
+        name: "SEV-SNP supported by KVM",
+        stat,
+        mesg: Some(mesg),
+    }
+}
+
+fn snp_enabled_in_kvm(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.read(KVM_SEV_SNP) {
+        Ok(result) if result.trim() == "1" || result.trim() == "Y" => {
+            (TestState::Pass, "enabled".to_string())
         }
+        Ok(result) => (
+            TestState::Fail,
+            format!(
+                "Error - contents read from {}: {}; load kvm_amd with sev_snp=1, and check dmesg if that fails",
+                KVM_SEV_SNP,
+                result.trim()
+            ),
+        ),
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - (unable to read {}): {}", KVM_SEV_SNP, e),
+        ),
+    };
 
-        return s
-            .parse::<usize>()
-            .context("unable to parse CPUID substring into usize");
+    TestResult {
+        name: "SEV-SNP enabled in KVM",
+        stat,
+        mesg: Some(mesg),
     }
+}
+
+fn snp_initialized(probe: &dyn Probe) -> TestResult {
+    let (stat, mesg) = match probe.snp_status() {
+        Ok(status) if !status.rmp_initialized => (
+            TestState::Fail,
+            "Error - the RMP is not initialized; check dmesg for errors from the ccp driver".to_string(),
+        ),
+        Ok(status) if !status.initialized() => (
+            TestState::Fail,
+            format!(
+                "Error - the SEV-SNP firmware is in state {}; check dmesg for errors from the ccp driver",
+                status.state
+            ),
+        ),
+        Ok(status) => (TestState::Pass, format!("{}", status.state)),
+        Err(e) => (
+            TestState::Fail,
+            format!("Error - unable to query the SEV-SNP firmware: {}", e),
+        ),
+    };
+
+    TestResult {
+        name: "SEV-SNP firmware initialized",
+        stat,
+        mesg: Some(mesg),
+    }
+}
 
-    Err(error::Context::new(
-        "unable to find AMD processor generation",
-        Box::<Error>::new(ErrorKind::InvalidData.into()),
-    ))
+fn current_gen(p: &dyn Probe) -> Result<SevGeneration> {
+    Ok(match cpu::Generation::detect(p)? {
+        cpu::Generation::Naples => SevGeneration::Sev,
+        cpu::Generation::Rome => SevGeneration::Es,
+        _ => SevGeneration::Snp,
+    })
 }
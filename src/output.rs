@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Selection of the console output format, and the JSON documents emitted
+//! when sevctl is driven by other programs.
+
+use crate::error::{Context, Contextual, Result};
+
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Console output format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::Text
+    }
+}
+
+impl FromStr for Output {
+    type Err = Context;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(Context::new(
+                &format!("unknown output format {}", s),
+                Box::<Error>::new(ErrorKind::InvalidInput.into()),
+            )),
+        }
+    }
+}
+
+impl Output {
+    /// Print `value` as a JSON document if JSON output was requested.
+    pub fn json<T: Serialize>(self, value: &T) -> Result<()> {
+        if self == Output::Json {
+            let doc = serde_json::to_string_pretty(value).context("unable to serialize output")?;
+            println!("{}", doc);
+        }
+
+        Ok(())
+    }
+}
+
+/// The verification result of one link of a certificate chain.
+#[derive(Serialize)]
+pub struct Link {
+    pub signer: String,
+    pub subject: String,
+    pub signature_valid: bool,
+    /// Only set for self-signed roots.
+    pub self_signature_valid: Option<bool>,
+}
+
+impl Link {
+    pub fn valid(&self) -> bool {
+        self.signature_valid && self.self_signature_valid.unwrap_or(true)
+    }
+}
+
+/// Describe an error and its chain of causes.
+pub fn error(err: &Context) -> Value {
+    let mut causes = vec![];
+    let mut cause: &(dyn std::error::Error + 'static) = err;
+    while let Some(c) = cause.source() {
+        causes.push(c.to_string());
+        cause = c;
+    }
+
+    json!({
+        "error": err.to_string(),
+        "causes": causes,
+        "exit_code": err.exit_code(),
+    })
+}
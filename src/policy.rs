@@ -0,0 +1,363 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Construction and review of SEV guest launch policies, so that operators
+//! don't have to compute the 32-bit policy word by hand.
+
+use crate::error::{Context, Result};
+use crate::output::Output;
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use ::sev::launch::sev;
+use serde_json::{json, Map, Value};
+use structopt::StructOpt;
+
+const NODBG: u32 = 1;
+const NOKS: u32 = 1 << 1;
+const ES: u32 = 1 << 2;
+const NOSEND: u32 = 1 << 3;
+const DOMAIN: u32 = 1 << 4;
+const SEV: u32 = 1 << 5;
+
+const RESERVED: u32 = 0xffc0;
+
+const API_MAJOR_SHIFT: u32 = 16;
+const API_MINOR_SHIFT: u32 = 24;
+
+/// Symbolic name, bit and description of every policy flag.
+const FLAGS: [(&str, u32, &str); 6] = [
+    ("nodbg", NODBG, "debugging of the guest is disallowed"),
+    ("noks", NOKS, "sharing keys with other guests is disallowed"),
+    ("es", ES, "SEV-ES is required"),
+    (
+        "nosend",
+        NOSEND,
+        "sending the guest to another platform is disallowed",
+    ),
+    (
+        "domain",
+        DOMAIN,
+        "the guest may only be sent within the domain",
+    ),
+    (
+        "sev",
+        SEV,
+        "the guest may only be sent to SEV-capable platforms",
+    ),
+];
+
+#[derive(StructOpt)]
+pub enum PolicyCmd {
+    #[structopt(about = "Print the flags and API version encoded in a launch policy")]
+    Decode {
+        #[structopt(help = "Launch policy as an integer or a comma-separated list of flags")]
+        policy: LaunchPolicy,
+    },
+}
+
+/// A SEV guest launch policy word.
+///
+/// Parsed either from an integer (decimal or `0x` hexadecimal), or from a
+/// comma-separated list such as `nodbg,noks,es,api-major=0,api-minor=17`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LaunchPolicy(u32);
+
+impl LaunchPolicy {
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn api_major(&self) -> u8 {
+        (self.0 >> API_MAJOR_SHIFT) as u8
+    }
+
+    pub fn api_minor(&self) -> u8 {
+        (self.0 >> API_MINOR_SHIFT) as u8
+    }
+
+    fn set_api_major(&mut self, major: u8) {
+        self.0 = (self.0 & !(0xff << API_MAJOR_SHIFT)) | (major as u32) << API_MAJOR_SHIFT;
+    }
+
+    fn set_api_minor(&mut self, minor: u8) {
+        self.0 = (self.0 & !(0xff << API_MINOR_SHIFT)) | (minor as u32) << API_MINOR_SHIFT;
+    }
+}
+
+impl From<LaunchPolicy> for sev::Policy {
+    fn from(policy: LaunchPolicy) -> Self {
+        sev::Policy::from(policy.0)
+    }
+}
+
+impl FromStr for LaunchPolicy {
+    type Err = Context;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            return u32::from_str_radix(hex, 16)
+                .map(LaunchPolicy)
+                .map_err(|e| invalid("invalid hexadecimal policy", e));
+        }
+
+        // The empty list of flags is the policy that sets none of them.
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            return s
+                .parse()
+                .map(LaunchPolicy)
+                .map_err(|e| invalid("invalid policy", e));
+        }
+
+        let mut policy = LaunchPolicy::default();
+        for token in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let token = token.to_ascii_lowercase();
+
+            if let Some(v) = token.strip_prefix("api-major=") {
+                policy.set_api_major(parse_version(v)?);
+            } else if let Some(v) = token.strip_prefix("api-minor=") {
+                policy.set_api_minor(parse_version(v)?);
+            } else {
+                match FLAGS.iter().find(|(name, _, _)| *name == token) {
+                    Some((_, bit, _)) => policy.0 |= bit,
+                    None => {
+                        return Err(Context::new(
+                            &format!("unknown policy flag {}", token),
+                            Box::<Error>::new(ErrorKind::InvalidInput.into()),
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+fn parse_version(v: &str) -> Result<u8> {
+    v.parse()
+        .map_err(|e| invalid("invalid policy API version", e))
+}
+
+fn invalid(msg: &str, err: std::num::ParseIntError) -> Context {
+    Context::new(msg, Box::new(Error::new(ErrorKind::InvalidInput, err)))
+}
+
+impl fmt::Display for LaunchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tokens: Vec<String> = FLAGS
+            .iter()
+            .filter(|(_, bit, _)| self.0 & bit != 0)
+            .map(|(name, _, _)| name.to_string())
+            .collect();
+
+        if self.api_major() != 0 || self.api_minor() != 0 {
+            tokens.push(format!("api-major={}", self.api_major()));
+            tokens.push(format!("api-minor={}", self.api_minor()));
+        }
+
+        write!(f, "{}", tokens.join(","))
+    }
+}
+
+/// Launch policy options accepted by `sevctl session`: an optional policy
+/// value, refined by individual flags.
+#[derive(StructOpt)]
+pub struct PolicyArgs {
+    #[structopt(
+        help = "Launch policy as an integer or a comma-separated list of flags (e.g. nodbg,noks,es)"
+    )]
+    pub policy: Option<LaunchPolicy>,
+
+    #[structopt(long, help = "Disallow debugging of the guest (NODBG)")]
+    pub no_debug: bool,
+
+    #[structopt(long, help = "Disallow sharing keys with other guests (NOKS)")]
+    pub no_key_sharing: bool,
+
+    #[structopt(long, help = "Require SEV-ES (ES)")]
+    pub es: bool,
+
+    #[structopt(long, help = "Disallow sending the guest to another platform (NOSEND)")]
+    pub no_send: bool,
+
+    #[structopt(long, help = "Only allow sending the guest within the domain (DOMAIN)")]
+    pub domain: bool,
+
+    #[structopt(long, help = "Only allow sending the guest to SEV platforms (SEV)")]
+    pub sev: bool,
+
+    #[structopt(long, help = "Minimum firmware API major version")]
+    pub api_major: Option<u8>,
+
+    #[structopt(long, help = "Minimum firmware API minor version")]
+    pub api_minor: Option<u8>,
+}
+
+impl PolicyArgs {
+    /// Combine the policy value and flags into a single launch policy.
+    pub fn policy(&self) -> Result<LaunchPolicy> {
+        let flags = [
+            (self.no_debug, NODBG),
+            (self.no_key_sharing, NOKS),
+            (self.es, ES),
+            (self.no_send, NOSEND),
+            (self.domain, DOMAIN),
+            (self.sev, SEV),
+        ];
+
+        let given = self.policy.is_some()
+            || flags.iter().any(|(set, _)| *set)
+            || self.api_major.is_some()
+            || self.api_minor.is_some();
+        if !given {
+            return Err(Context::new(
+                "a launch policy value or policy flags are required",
+                Box::<Error>::new(ErrorKind::InvalidInput.into()),
+            ));
+        }
+
+        let mut policy = self.policy.unwrap_or_default();
+        for (set, bit) in flags.iter() {
+            if *set {
+                policy.0 |= bit;
+            }
+        }
+        if let Some(major) = self.api_major {
+            policy.set_api_major(major);
+        }
+        if let Some(minor) = self.api_minor {
+            policy.set_api_minor(minor);
+        }
+
+        Ok(policy)
+    }
+}
+
+pub fn cmd(cmd: PolicyCmd, output: Output) -> Result<()> {
+    match cmd {
+        PolicyCmd::Decode { policy } if output == Output::Json => output.json(&decode(policy))?,
+        PolicyCmd::Decode { policy } => {
+            println!("{:#010x} ({})", policy.bits(), policy);
+            for (name, bit, desc) in FLAGS.iter() {
+                let set = if policy.bits() & bit != 0 {
+                    "yes"
+                } else {
+                    "no"
+                };
+                println!("  {:<10} {:<4} {}", name, set, desc);
+            }
+            if policy.bits() & RESERVED != 0 {
+                println!("  {:<10} {:#06x}", "reserved", policy.bits() & RESERVED);
+            }
+            println!("  {:<10} {}", "api-major", policy.api_major());
+            println!("  {:<10} {}", "api-minor", policy.api_minor());
+        }
+    }
+
+    Ok(())
+}
+
+/// The fields of a policy, as printed by `policy decode --output json`.
+fn decode(policy: LaunchPolicy) -> Value {
+    let flags: Map<String, Value> = FLAGS
+        .iter()
+        .map(|(name, bit, _)| (name.to_string(), Value::from(policy.bits() & bit != 0)))
+        .collect();
+
+    json!({
+        "policy": policy.bits(),
+        "symbolic": policy.to_string(),
+        "flags": flags,
+        "reserved": policy.bits() & RESERVED,
+        "api_major": policy.api_major(),
+        "api_minor": policy.api_minor(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EXIT_INVALID_INPUT;
+
+    fn parse(s: &str) -> LaunchPolicy {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn integers_and_flags_parse() {
+        assert_eq!(parse("3"), LaunchPolicy(NODBG | NOKS));
+        assert_eq!(parse("0x110003"), LaunchPolicy(0x11_0003));
+        assert_eq!(parse("0X3F"), LaunchPolicy(0x3f));
+        assert_eq!(parse(" NODBG, noks ,"), LaunchPolicy(NODBG | NOKS));
+        assert_eq!(
+            parse("nodbg,noks,es,api-major=0,api-minor=17"),
+            LaunchPolicy(0x1100_0007)
+        );
+        assert_eq!(parse(""), LaunchPolicy(0));
+    }
+
+    #[test]
+    fn symbolic_form_round_trips() {
+        let versions = [(0, 0), (0, 17), (1, 55), (255, 255)];
+
+        for bits in 0..0x40 {
+            for (major, minor) in versions.iter() {
+                let mut policy = LaunchPolicy(bits);
+                policy.set_api_major(*major);
+                policy.set_api_minor(*minor);
+
+                assert_eq!(parse(&policy.to_string()), policy, "{}", policy);
+            }
+        }
+
+        assert_eq!(
+            parse("0x11000007").to_string(),
+            "nodbg,noks,es,api-major=0,api-minor=17"
+        );
+        assert_eq!(LaunchPolicy(0).to_string(), "");
+    }
+
+    #[test]
+    fn unknown_flags_and_bad_numbers_are_rejected() {
+        for s in &[
+            "nodbg,debug",
+            "nodbg;noks",
+            "api-major=256",
+            "api-minor=-1",
+            "api-version=1",
+            "0xzz",
+            "4294967296",
+        ] {
+            let err = s.parse::<LaunchPolicy>().expect_err(s);
+            assert_eq!(err.exit_code(), EXIT_INVALID_INPUT, "{}", s);
+        }
+    }
+
+    #[test]
+    fn decode_reports_every_field() {
+        let decoded = decode(LaunchPolicy(0x1100_8005));
+
+        assert_eq!(
+            decoded,
+            json!({
+                "policy": 0x1100_8005u32,
+                "symbolic": "nodbg,es,api-major=0,api-minor=17",
+                "flags": {
+                    "nodbg": true,
+                    "noks": false,
+                    "es": true,
+                    "nosend": false,
+                    "domain": false,
+                    "sev": false,
+                },
+                "reserved": 0x8000,
+                "api_major": 0,
+                "api_minor": 17,
+            })
+        );
+    }
+}
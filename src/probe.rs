@@ -0,0 +1,456 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The host facts `sevctl ok` inspects, behind a trait so that the checks
+//! can run against a recorded host profile as well as the live host.
+//!
+//! A profile is a JSON document holding the outcome of every probe made
+//! while recording it (the SEV-SNP platform status is left out here):
+//!
+//! ```text
+//! {
+//!   "cpuid": { "0x8000001f": { "eax": 115611, "ebx": 369, "ecx": 509, "edx": 1 } },
+//!   "files": { "/sys/module/kvm_amd/parameters/sev": { "Ok": "Y\n" } },
+//!   "dirs": { "/sys/class/iommu": { "Ok": [ "ivhd0", "ivhd1" ] } },
+//!   "msrs": { "0xc0010010": { "Ok": 25427968 } },
+//!   "devices": {
+//!     "/dev/sev": {
+//!       "read": { "Ok": null },
+//!       "write": { "Err": { "errno": 13, "message": "Permission denied (os error 13)" } }
+//!     }
+//!   },
+//!   "kvm_api_version": { "Ok": 12 },
+//!   "memlock": { "Ok": { "soft": 65536, "hard": 65536 } },
+//!   "sev_status": {
+//!     "Ok": { "version": { "major": 1, "minor": 55, "build": 21 }, "encrypted_state": true }
+//!   }
+//! }
+//! ```
+
+use crate::error::{self, Contextual, Result};
+use crate::firmware::Platform;
+use crate::snp::{self, FirmwareVersion, SnpStatus};
+
+use std::arch::x86_64;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::mem::MaybeUninit;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use ::sev::firmware::PlatformStatusFlags;
+use serde::{Deserialize, Serialize};
+
+/// The registers a CPUID leaf returns.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct Cpuid {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Soft and hard limits of a resource.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Rlimit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+/// The host facts `sevctl ok` inspects.
+pub trait Probe {
+    /// Execute CPUID for `leaf` (with subleaf 0).
+    fn cpuid(&self, leaf: u32) -> Cpuid;
+
+    /// Read a sysfs, procfs or other text file.
+    fn read(&self, path: &str) -> std::io::Result<String>;
+
+    /// List the names of the entries of a directory, sorted.
+    fn list(&self, path: &str) -> std::io::Result<Vec<String>>;
+
+    /// Read a model-specific register of the first CPU.
+    fn msr(&self, msr: u32) -> std::io::Result<u64>;
+
+    /// Open a device node for reading or writing.
+    fn open(&self, path: &str, write: bool) -> std::io::Result<()>;
+
+    /// The KVM API version reported by /dev/kvm.
+    fn kvm_api_version(&self) -> std::io::Result<i32>;
+
+    /// The limits on locked memory of this process.
+    fn memlock(&self) -> std::io::Result<Rlimit>;
+
+    /// The status of the SEV firmware.
+    fn sev_status(&self) -> std::io::Result<SevStatus>;
+
+    /// The status of the SEV-SNP firmware.
+    fn snp_status(&self) -> std::io::Result<SnpStatus>;
+}
+
+/// What `sevctl ok` needs of the SEV platform status.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct SevStatus {
+    pub version: FirmwareVersion,
+    /// The firmware supports SEV-ES.
+    pub encrypted_state: bool,
+}
+
+/// Probes the live host, querying the SEV firmware through `Platform`.
+pub struct Host<'a>(pub &'a Platform);
+
+/// The I/O error among the causes of `err`, or else an error carrying the
+/// messages of all of them.
+fn io_error(err: &(dyn std::error::Error + 'static)) -> Error {
+    let mut msgs = vec![];
+    let mut cause = Some(err);
+    while let Some(e) = cause {
+        if let Some(e) = e.downcast_ref::<Error>() {
+            return match e.raw_os_error() {
+                Some(errno) => Error::from_raw_os_error(errno),
+                None => Error::new(e.kind(), e.to_string()),
+            };
+        }
+        msgs.push(e.to_string());
+        cause = e.source();
+    }
+
+    Error::new(ErrorKind::Other, msgs.join(": "))
+}
+
+impl Probe for Host<'_> {
+    fn cpuid(&self, leaf: u32) -> Cpuid {
+        let res = unsafe { x86_64::__cpuid(leaf) };
+
+        Cpuid {
+            eax: res.eax,
+            ebx: res.ebx,
+            ecx: res.ecx,
+            edx: res.edx,
+        }
+    }
+
+    fn read(&self, path: &str) -> std::io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn list(&self, path: &str) -> std::io::Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(path)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    fn msr(&self, msr: u32) -> std::io::Result<u64> {
+        let mut value = [0u8; 8];
+        fs::File::open("/dev/cpu/0/msr")?.read_exact_at(&mut value, msr.into())?;
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn open(&self, path: &str, write: bool) -> std::io::Result<()> {
+        fs::OpenOptions::new()
+            .read(!write)
+            .write(write)
+            .open(path)
+            .map(|_| ())
+    }
+
+    fn kvm_api_version(&self) -> std::io::Result<i32> {
+        let kvm = fs::File::open("/dev/kvm")?;
+
+        match unsafe { libc::ioctl(kvm.as_raw_fd(), 0xAE00, 0) } {
+            v if v < 0 => Err(Error::last_os_error()),
+            v => Ok(v),
+        }
+    }
+
+    fn memlock(&self) -> std::io::Result<Rlimit> {
+        let mut rlimit = MaybeUninit::uninit();
+        if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, rlimit.as_mut_ptr()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let r = unsafe { rlimit.assume_init() };
+
+        Ok(Rlimit {
+            soft: r.rlim_cur,
+            hard: r.rlim_max,
+        })
+    }
+
+    fn sev_status(&self) -> std::io::Result<SevStatus> {
+        let mut fw = self.0.firmware().map_err(|e| io_error(&e))?;
+        let status = fw
+            .platform_status()
+            .map_err(|e| io_error(&error::Error::from(e)))?;
+
+        Ok(SevStatus {
+            version: FirmwareVersion {
+                major: status.build.version.major,
+                minor: status.build.version.minor,
+                build: status.build.build,
+            },
+            encrypted_state: status.flags.contains(PlatformStatusFlags::ENCRYPTED_STATE),
+        })
+    }
+
+    fn snp_status(&self) -> std::io::Result<SnpStatus> {
+        snp::platform_status(self.0).map_err(|e| io_error(&e))
+    }
+}
+
+/// A recorded failure. Operating system errors replay as the same error
+/// number; anything else replays as its message.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Failure {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errno: Option<i32>,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl From<&Error> for Failure {
+    fn from(e: &Error) -> Self {
+        Self {
+            errno: e.raw_os_error(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<&Failure> for Error {
+    fn from(f: &Failure) -> Self {
+        match f.errno {
+            Some(errno) => Error::from_raw_os_error(errno),
+            None => Error::new(ErrorKind::Other, f.message.clone()),
+        }
+    }
+}
+
+/// The recorded outcome of a probe.
+pub type Outcome<T> = std::result::Result<T, Failure>;
+
+/// The recorded outcome of opening a device node.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Device {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read: Option<Outcome<()>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write: Option<Outcome<()>>,
+}
+
+/// A recorded host. Probes that weren't recorded fail with `NotFound`,
+/// except CPUID, which returns zeros like an unsupported leaf does.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Profile {
+    /// Keyed by leaf, in hex.
+    #[serde(default)]
+    pub cpuid: BTreeMap<String, Cpuid>,
+    #[serde(default)]
+    pub files: BTreeMap<String, Outcome<String>>,
+    #[serde(default)]
+    pub dirs: BTreeMap<String, Outcome<Vec<String>>>,
+    /// Keyed by register, in hex.
+    #[serde(default)]
+    pub msrs: BTreeMap<String, Outcome<u64>>,
+    #[serde(default)]
+    pub devices: BTreeMap<String, Device>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kvm_api_version: Option<Outcome<i32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memlock: Option<Outcome<Rlimit>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sev_status: Option<Outcome<SevStatus>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snp_status: Option<Outcome<SnpStatus>>,
+}
+
+impl Profile {
+    /// A host whose CPUID reports `vendor` and the signature `eax`, and
+    /// nothing else.
+    #[cfg(test)]
+    pub fn with_cpu(vendor: &[u8; 12], eax: u32) -> Self {
+        let reg =
+            |n: usize| u32::from_le_bytes([vendor[n], vendor[n + 1], vendor[n + 2], vendor[n + 3]]);
+
+        let mut profile = Self::default();
+        let leaf0 = Cpuid {
+            ebx: reg(0),
+            edx: reg(4),
+            ecx: reg(8),
+            ..Default::default()
+        };
+        let leaf1 = Cpuid {
+            eax,
+            ..Default::default()
+        };
+        profile.cpuid.insert("0x00000000".to_string(), leaf0);
+        profile.cpuid.insert("0x00000001".to_string(), leaf1);
+        profile
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read(path).context(format!("unable to read {}", path.display()))?;
+        serde_json::from_slice(&json).context(format!("invalid host profile {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json =
+            serde_json::to_string_pretty(self).context("unable to serialize host profile")?;
+        fs::write(path, json + "\n").context(format!("unable to write {}", path.display()))
+    }
+}
+
+/// The key of a CPUID leaf or MSR in a profile.
+fn hex(leaf: u32) -> String {
+    format!("{:#010x}", leaf)
+}
+
+/// Replay a recorded outcome, or fail if it wasn't recorded.
+fn replay<T: Clone>(what: &str, outcome: Option<&Outcome<T>>) -> std::io::Result<T> {
+    match outcome {
+        Some(Ok(v)) => Ok(v.clone()),
+        Some(Err(f)) => Err(f.into()),
+        None => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("{} is not in the host profile", what),
+        )),
+    }
+}
+
+impl Probe for Profile {
+    fn cpuid(&self, leaf: u32) -> Cpuid {
+        self.cpuid.get(&hex(leaf)).copied().unwrap_or_default()
+    }
+
+    fn read(&self, path: &str) -> std::io::Result<String> {
+        replay(path, self.files.get(path))
+    }
+
+    fn list(&self, path: &str) -> std::io::Result<Vec<String>> {
+        replay(path, self.dirs.get(path))
+    }
+
+    fn msr(&self, msr: u32) -> std::io::Result<u64> {
+        replay(&format!("MSR {}", hex(msr)), self.msrs.get(&hex(msr)))
+    }
+
+    fn open(&self, path: &str, write: bool) -> std::io::Result<()> {
+        let device = self.devices.get(path);
+        match write {
+            false => replay(path, device.and_then(|d| d.read.as_ref())),
+            true => replay(path, device.and_then(|d| d.write.as_ref())),
+        }
+    }
+
+    fn kvm_api_version(&self) -> std::io::Result<i32> {
+        replay("the KVM API version", self.kvm_api_version.as_ref())
+    }
+
+    fn memlock(&self) -> std::io::Result<Rlimit> {
+        replay("the memlock limit", self.memlock.as_ref())
+    }
+
+    fn sev_status(&self) -> std::io::Result<SevStatus> {
+        replay("the SEV platform status", self.sev_status.as_ref())
+    }
+
+    fn snp_status(&self) -> std::io::Result<SnpStatus> {
+        replay("the SEV-SNP platform status", self.snp_status.as_ref())
+    }
+}
+
+/// Passes probes through to another backend, recording their outcomes.
+pub struct Recorder<'a> {
+    inner: &'a dyn Probe,
+    profile: RefCell<Profile>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(inner: &'a dyn Probe) -> Self {
+        Self {
+            inner,
+            profile: RefCell::new(Profile::default()),
+        }
+    }
+
+    pub fn into_profile(self) -> Profile {
+        self.profile.into_inner()
+    }
+}
+
+fn record<T: Clone>(outcome: &std::io::Result<T>) -> Outcome<T> {
+    match outcome {
+        Ok(v) => Ok(v.clone()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl Probe for Recorder<'_> {
+    fn cpuid(&self, leaf: u32) -> Cpuid {
+        let res = self.inner.cpuid(leaf);
+        self.profile.borrow_mut().cpuid.insert(hex(leaf), res);
+        res
+    }
+
+    fn read(&self, path: &str) -> std::io::Result<String> {
+        let res = self.inner.read(path);
+        let mut profile = self.profile.borrow_mut();
+        profile.files.insert(path.to_string(), record(&res));
+        res
+    }
+
+    fn list(&self, path: &str) -> std::io::Result<Vec<String>> {
+        let res = self.inner.list(path);
+        let mut profile = self.profile.borrow_mut();
+        profile.dirs.insert(path.to_string(), record(&res));
+        res
+    }
+
+    fn msr(&self, msr: u32) -> std::io::Result<u64> {
+        let res = self.inner.msr(msr);
+        self.profile
+            .borrow_mut()
+            .msrs
+            .insert(hex(msr), record(&res));
+        res
+    }
+
+    fn open(&self, path: &str, write: bool) -> std::io::Result<()> {
+        let res = self.inner.open(path, write);
+        let mut profile = self.profile.borrow_mut();
+        let device = profile.devices.entry(path.to_string()).or_default();
+        match write {
+            false => device.read = Some(record(&res)),
+            true => device.write = Some(record(&res)),
+        }
+        res
+    }
+
+    fn kvm_api_version(&self) -> std::io::Result<i32> {
+        let res = self.inner.kvm_api_version();
+        self.profile.borrow_mut().kvm_api_version = Some(record(&res));
+        res
+    }
+
+    fn memlock(&self) -> std::io::Result<Rlimit> {
+        let res = self.inner.memlock();
+        self.profile.borrow_mut().memlock = Some(record(&res));
+        res
+    }
+
+    fn sev_status(&self) -> std::io::Result<SevStatus> {
+        let res = self.inner.sev_status();
+        self.profile.borrow_mut().sev_status = Some(record(&res));
+        res
+    }
+
+    fn snp_status(&self) -> std::io::Result<SnpStatus> {
+        let res = self.inner.snp_status();
+        self.profile.borrow_mut().snp_status = Some(record(&res));
+        res
+    }
+}
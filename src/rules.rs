@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Thresholds that `sevctl ok` holds the host to, beyond the presence of
+//! each feature.
+//!
+//! A rules file holds one `key = value` setting per line, like the
+//! configuration file; every setting is optional:
+//!
+//! ```text
+//! # Locked memory limits, in bytes or `unlimited`.
+//! min-memlock-soft = 8388608
+//! min-memlock-hard = unlimited
+//! # Encrypted guests the processor can run simultaneously.
+//! min-encrypted-guests = 500
+//! # SEV firmware version, as printed by `sevctl show version`.
+//! min-firmware = 1.55.21
+//! # SVN of each component of the current SEV-SNP TCB.
+//! min-snp-tcb = bootloader=3 tee=0 snp=8 microcode=115
+//! ```
+
+use crate::config::{invalid, number, settings};
+use crate::error::Result;
+use crate::snp::{FirmwareVersion, TcbVersion};
+
+use std::path::Path;
+
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    pub min_memlock_soft: Option<u64>,
+    pub min_memlock_hard: Option<u64>,
+    pub min_encrypted_guests: Option<u32>,
+    pub min_firmware: Option<FirmwareVersion>,
+    pub min_snp_tcb: Option<TcbVersion>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut rules = Self::default();
+        for (n, key, value) in settings(path, "rules file")? {
+            match &key[..] {
+                "min-memlock-soft" => rules.min_memlock_soft = Some(limit(path, n, &key, &value)?),
+                "min-memlock-hard" => rules.min_memlock_hard = Some(limit(path, n, &key, &value)?),
+                "min-encrypted-guests" => {
+                    rules.min_encrypted_guests = Some(number(path, n, &key, &value)?)
+                }
+                "min-firmware" => {
+                    rules.min_firmware = Some(firmware(&value).ok_or_else(|| {
+                        invalid(
+                            path,
+                            n,
+                            "min-firmware must be of the form MAJOR.MINOR.BUILD",
+                        )
+                    })?)
+                }
+                "min-snp-tcb" => {
+                    rules.min_snp_tcb = Some(tcb(&value).ok_or_else(|| {
+                        invalid(
+                        path,
+                        n,
+                        "min-snp-tcb must list COMPONENT=SVN for bootloader, tee, snp or microcode",
+                    )
+                    })?)
+                }
+                _ => return Err(invalid(path, n, &format!("unknown rule {}", key))),
+            }
+        }
+
+        Ok(rules)
+    }
+}
+
+/// `a` is at least `min` in every component.
+pub fn tcb_meets(a: &TcbVersion, min: &TcbVersion) -> bool {
+    a.bootloader >= min.bootloader
+        && a.tee >= min.tee
+        && a.snp >= min.snp
+        && a.microcode >= min.microcode
+}
+
+fn limit(path: &Path, line: usize, key: &str, value: &str) -> Result<u64> {
+    match value {
+        "unlimited" => Ok(libc::RLIM_INFINITY),
+        _ => number(path, line, key, value),
+    }
+}
+
+fn firmware(value: &str) -> Option<FirmwareVersion> {
+    let mut parts = value.split('.').map(|p| p.parse().ok());
+
+    let version = FirmwareVersion {
+        major: parts.next()??,
+        minor: parts.next()??,
+        build: parts.next()??,
+    };
+
+    match parts.next() {
+        None => Some(version),
+        Some(_) => None,
+    }
+}
+
+/// Components left out must be at least 0, but at least one must be given.
+fn tcb(value: &str) -> Option<TcbVersion> {
+    let mut tcb = TcbVersion::default();
+    let mut parts = value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .peekable();
+    parts.peek()?;
+
+    for part in parts {
+        let pos = part.find('=')?;
+        let svn = part[pos + 1..].parse().ok()?;
+        match &part[..pos] {
+            "bootloader" => tcb.bootloader = svn,
+            "tee" => tcb.tee = svn,
+            "snp" => tcb.snp = svn,
+            "microcode" => tcb.microcode = svn,
+            _ => return None,
+        }
+    }
+
+    Some(tcb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firmware_needs_exactly_three_components() {
+        let version = FirmwareVersion {
+            major: 1,
+            minor: 55,
+            build: 21,
+        };
+        assert_eq!(firmware("1.55.21"), Some(version));
+
+        for value in &[
+            "",
+            "1.55",
+            "1.55.21.4",
+            "1.55.",
+            "1..21",
+            "1.55.256",
+            "1.55.-1",
+            "v1.55.21",
+        ] {
+            assert_eq!(firmware(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn tcb_components_are_separated_by_commas_or_whitespace() {
+        let expected = TcbVersion {
+            bootloader: 3,
+            tee: 0,
+            snp: 8,
+            microcode: 115,
+        };
+        for value in &[
+            "bootloader=3,tee=0,snp=8,microcode=115",
+            "bootloader=3 tee=0 snp=8 microcode=115",
+            "bootloader=3, tee=0,\tsnp=8  microcode=115",
+        ] {
+            assert_eq!(tcb(value), Some(expected), "{}", value);
+        }
+
+        let snp_only = TcbVersion {
+            snp: 8,
+            ..Default::default()
+        };
+        assert_eq!(tcb("snp=8"), Some(snp_only));
+    }
+
+    #[test]
+    fn tcb_rejects_malformed_components() {
+        for value in &[
+            "", " , ", "fmc=1", "snp", "snp=", "snp=256", "snp=-1", "snp=8=9", "SNP=8",
+        ] {
+            assert_eq!(tcb(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn limits_are_numbers_or_unlimited() {
+        let path = Path::new("rules");
+        assert_eq!(
+            limit(path, 0, "min-memlock-soft", "8388608").unwrap(),
+            8388608
+        );
+        assert_eq!(
+            limit(path, 0, "min-memlock-soft", "unlimited").unwrap(),
+            libc::RLIM_INFINITY
+        );
+
+        for value in &["", "-1", "8M", "infinity", "Unlimited"] {
+            let err = limit(path, 2, "min-memlock-soft", value).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "rules:3: min-memlock-soft must be a number",
+                "{}",
+                value
+            );
+            assert_eq!(err.exit_code(), crate::error::EXIT_INVALID_INPUT);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Packaging of guest secrets for LAUNCH_SECRET, in the layout consumed by
+//! QMP `sev-inject-launch-secret` and the OVMF secret table.
+
+use crate::error::{Context, Contextual, Result};
+use crate::measurement;
+use crate::output::Output;
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{encrypt, Cipher};
+use serde_json::json;
+use structopt::StructOpt;
+
+/// GUID identifying the secret table parsed by OVMF.
+const SEV_SECRET_TABLE_GUID: &str = "1e74f542-71dd-4d66-963e-ef4287ff173b";
+
+const TEK_LEN: usize = 16;
+const IV_LEN: usize = 16;
+
+#[derive(StructOpt)]
+pub enum SecretCmd {
+    #[structopt(about = "Build a launch secret packet for `sev-inject-launch-secret`")]
+    Build(BuildArgs),
+}
+
+#[derive(StructOpt)]
+pub struct BuildArgs {
+    #[structopt(short, long, help = "Name used to identify file names")]
+    pub name: Option<String>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Path of the TEK file written by `sevctl session`"
+    )]
+    pub tek: PathBuf,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Path of the TIK file written by `sevctl session`"
+    )]
+    pub tik: PathBuf,
+
+    #[structopt(
+        long,
+        help = "Base64-encoded measurement || mnonce, as returned by `query-sev-launch-measure`"
+    )]
+    pub measurement: String,
+
+    #[structopt(
+        long = "secret",
+        required = true,
+        number_of_values = 1,
+        help = "Secret table entry given as GUID=FILE (may be repeated)"
+    )]
+    pub secrets: Vec<SecretEntry>,
+}
+
+/// A single `GUID=FILE` secret table entry.
+pub struct SecretEntry {
+    guid: [u8; 16],
+    path: PathBuf,
+}
+
+impl FromStr for SecretEntry {
+    type Err = Context;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let pos = s.find('=').ok_or_else(|| {
+            Context::new(
+                &format!("secret {} is not of the form GUID=FILE", s),
+                Box::<Error>::new(ErrorKind::InvalidInput.into()),
+            )
+        })?;
+
+        Ok(Self {
+            guid: measurement::guid_le(&s[..pos])?,
+            path: PathBuf::from(&s[pos + 1..]),
+        })
+    }
+}
+
+pub fn cmd(cmd: SecretCmd, output: Output) -> Result<()> {
+    match cmd {
+        SecretCmd::Build(args) => build(args, output),
+    }
+}
+
+fn build(args: BuildArgs, output: Output) -> Result<()> {
+    let (header_fname, secret_fname) = file_names(args.name);
+
+    let tek = read_tek(&args.tek)?;
+    let tik = measurement::read_tik(&args.tik)?;
+    let (measure, _) = measurement::split_measurement(&args.measurement)?;
+
+    let table = secret_table(&args.secrets)?;
+
+    let mut iv = [0u8; IV_LEN];
+    openssl::rand::rand_bytes(&mut iv).context("unable to generate secret IV")?;
+
+    let (header, data) = packet(&tek, &tik, &iv, &measure, &table)?;
+
+    fs::write(&header_fname, base64::encode(header))
+        .context("could not write base64 encoded secret header")?;
+    fs::write(&secret_fname, base64::encode(data))
+        .context("could not write base64 encoded secret")?;
+
+    output.json(&json!({
+        "header": header_fname,
+        "secret": secret_fname,
+    }))
+}
+
+/// Encrypt the secret table with the TEK and authenticate it with the TIK,
+/// returning the packet header and the encrypted table.
+fn packet(
+    tek: &[u8],
+    tik: &[u8],
+    iv: &[u8],
+    measure: &[u8],
+    table: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let data = encrypt(Cipher::aes_128_ctr(), tek, Some(iv), table)
+        .context("unable to encrypt secret table with TEK")?;
+
+    let flags = 0u32;
+    let len = data.len() as u32;
+
+    // HMAC(TIK, 0x01 || FLAGS || IV || GUEST_LENGTH || TRANS_LENGTH || DATA || MEASURE)
+    let key = PKey::hmac(tik).context("failed to load TIK as HMAC key")?;
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).context("failed to create HMAC signer")?;
+    signer
+        .update(&[0x01])
+        .and_then(|_| signer.update(&flags.to_le_bytes()))
+        .and_then(|_| signer.update(iv))
+        .and_then(|_| signer.update(&len.to_le_bytes()))
+        .and_then(|_| signer.update(&len.to_le_bytes()))
+        .and_then(|_| signer.update(&data))
+        .and_then(|_| signer.update(measure))
+        .context("failed to compute secret HMAC")?;
+    let hmac = signer
+        .sign_to_vec()
+        .context("failed to compute secret HMAC")?;
+
+    // Packet header: FLAGS || IV || HMAC
+    let mut header = Vec::with_capacity(4 + IV_LEN + hmac.len());
+    header.extend_from_slice(&flags.to_le_bytes());
+    header.extend_from_slice(iv);
+    header.extend_from_slice(&hmac);
+
+    Ok((header, data))
+}
+
+/// Lay out the secret table: a header GUID and total length, followed by
+/// GUID || length || data for each entry, padded to the AES block size.
+fn secret_table(entries: &[SecretEntry]) -> Result<Vec<u8>> {
+    let mut table = vec![];
+    table.extend_from_slice(&measurement::guid_le(SEV_SECRET_TABLE_GUID)?);
+    table.extend_from_slice(&0u32.to_le_bytes());
+
+    for entry in entries {
+        let data = fs::read(&entry.path)
+            .context(format!("failed to read secret {}", entry.path.display()))?;
+
+        table.extend_from_slice(&entry.guid);
+        table.extend_from_slice(&((16 + 4 + data.len()) as u32).to_le_bytes());
+        table.extend_from_slice(&data);
+    }
+
+    let len = table.len() as u32;
+    table[16..20].copy_from_slice(&len.to_le_bytes());
+    table.resize((table.len() + 15) & !15, 0);
+
+    Ok(table)
+}
+
+fn read_tek(path: &Path) -> Result<Vec<u8>> {
+    let tek = fs::read(path).context(format!("failed to read TEK file {}", path.display()))?;
+    if tek.len() != TEK_LEN {
+        return Err(Context::new(
+            &format!("TEK file must be {} bytes", TEK_LEN),
+            Box::<Error>::new(ErrorKind::InvalidData.into()),
+        ));
+    }
+
+    Ok(tek)
+}
+
+fn file_names(name: Option<String>) -> (String, String) {
+    let prefix = match name {
+        Some(n) => n,
+        None => "vm".to_string(),
+    };
+
+    let header = prefix.clone() + "_secret_header.b64";
+    let secret = prefix + "_secret.b64";
+
+    (header, secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error;
+
+    const PASSPHRASE_GUID: &str = "736869e5-84f0-4973-92ec-06879ce3da0b";
+    const OTHER_GUID: &str = "e6f5a162-d67f-4750-a67c-5d065f2a9910";
+
+    /// The table of the passphrase and other secrets below, as computed
+    /// independently of this module from the OVMF secret table layout.
+    const TABLE: &str = concat!(
+        "42f5741edd71664d963eef4287ff173b5a000000",
+        "e5696873f084734992ec06879ce3da0b1e000000",
+        "70617373706872617365",
+        "62a1f5e67fd65047a67c5d065f2a991028000000",
+        "303132333435363738396162636465666768696a",
+        "000000000000",
+    );
+
+    #[test]
+    fn entries_pair_a_guid_with_a_file() {
+        let entry: SecretEntry = format!("{}=/run/secrets/passphrase", PASSPHRASE_GUID)
+            .parse()
+            .unwrap();
+        assert_eq!(hex::encode(entry.guid), "e5696873f084734992ec06879ce3da0b");
+        assert_eq!(entry.path, Path::new("/run/secrets/passphrase"));
+
+        for s in &[
+            PASSPHRASE_GUID,
+            "passphrase=/run/secrets/passphrase",
+            "=/run/secrets/passphrase",
+        ] {
+            let err = s.parse::<SecretEntry>().err().expect(s);
+            assert_eq!(err.exit_code(), error::EXIT_INVALID_INPUT, "{}", s);
+        }
+    }
+
+    #[test]
+    fn table_holds_lengths_and_is_padded() {
+        let dir = std::env::temp_dir().join(format!("sevctl-secret-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("passphrase"), "passphrase").unwrap();
+        fs::write(dir.join("other"), "0123456789abcdefghij").unwrap();
+
+        let entries = [
+            format!("{}={}", PASSPHRASE_GUID, dir.join("passphrase").display()),
+            format!("{}={}", OTHER_GUID, dir.join("other").display()),
+        ];
+        let entries: Vec<SecretEntry> = entries.iter().map(|e| e.parse().unwrap()).collect();
+        let table = secret_table(&entries).unwrap();
+
+        // 20 bytes of header, 30 and 40 bytes of entries, 6 of padding.
+        assert_eq!(table.len(), 96);
+        assert_eq!(&table[16..20], &90u32.to_le_bytes());
+        assert_eq!(&table[36..40], &30u32.to_le_bytes());
+        assert_eq!(&table[66..70], &40u32.to_le_bytes());
+        assert_eq!(hex::encode(&table), TABLE);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn packet_known_answer() {
+        let tek: Vec<u8> = (0x00..0x10).collect();
+        let tik: Vec<u8> = (0x10..0x20).collect();
+        let iv: Vec<u8> = (0x20..0x30).collect();
+        let measure =
+            hex::decode("0d5f16a47e2d10a9d4d53703a1bdf4f5a9035b0ef3dd5d0dca4c388185f190ad")
+                .unwrap();
+
+        let (header, data) =
+            packet(&tek, &tik, &iv, &measure, &hex::decode(TABLE).unwrap()).unwrap();
+
+        assert_eq!(
+            hex::encode(header),
+            concat!(
+                "00000000202122232425262728292a2b2c2d2e2f",
+                "579dbb7fe1db13748ffe886ad470c5087e9eda413089a034b0ca4329ec321589",
+            )
+        );
+        assert_eq!(
+            hex::encode(data),
+            concat!(
+                "191d0a3086351ad9dd1f26edf0a9d7e311a41c22c7e750dbf2e74db1e273ce70",
+                "e86c001e3a536610376c40a28697209a5c3132bb2c3c459a3bf5ea77fb0e9643",
+                "179cfa0686b1610d42f3d2a6a3bd5df80bc2480a6bac4fa6cbcbcf4a838860d8",
+            )
+        );
+    }
+}
@@ -1,6 +1,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::error::{Contextual, Result};
+use crate::output::Output;
+use crate::policy::LaunchPolicy;
 
 use std::convert::{From, TryFrom};
 use std::fs;
@@ -12,8 +14,9 @@ use ::sev::certs::sev::Certificate;
 use ::sev::{launch::sev, session};
 
 use codicon::{Decoder, Encoder};
+use serde_json::json;
 
-pub fn cmd(name: Option<String>, pdh: PathBuf, policy: u32) -> Result<()> {
+pub fn cmd(name: Option<String>, pdh: PathBuf, policy: LaunchPolicy, output: Output) -> Result<()> {
     let (tik_fname, tek_fname, godh_fname, session_fname) = file_names(name);
 
     let session = session::Session::try_from(sev::Policy::from(policy))
@@ -44,18 +47,23 @@ pub fn cmd(name: Option<String>, pdh: PathBuf, policy: u32) -> Result<()> {
     };
     let godh = base64::encode(godh);
 
-    let mut tik_file = fs::File::create(tik_fname).context("TIK file could not be created")?;
-    let mut tek_file = fs::File::create(tek_fname).context("TEK file could not be created")?;
+    let mut tik_file = fs::File::create(&tik_fname).context("TIK file could not be created")?;
+    let mut tek_file = fs::File::create(&tek_fname).context("TEK file could not be created")?;
 
     tik.encode(&mut tik_file, ())
         .context("TIK could not be encoded into file")?;
     tek.encode(&mut tek_file, ())
         .context("TEK could not be encoded into file")?;
 
-    fs::write(session_fname, launch_blob).context("could not write base64 encoded session")?;
-    fs::write(godh_fname, godh).context("could not write base64 encoded godh")?;
+    fs::write(&session_fname, launch_blob).context("could not write base64 encoded session")?;
+    fs::write(&godh_fname, godh).context("could not write base64 encoded godh")?;
 
-    Ok(())
+    output.json(&json!({
+        "tik": tik_fname,
+        "tek": tek_fname,
+        "godh": godh_fname,
+        "session": session_fname,
+    }))
 }
 
 fn file_names(name: Option<String>) -> (String, String, String, String) {
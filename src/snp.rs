@@ -0,0 +1,583 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! SEV-SNP firmware commands that are issued directly through the `/dev/sev`
+//! ioctl interface.
+
+use crate::cache::Cache;
+use crate::error::{self, Context, Contextual, Result};
+use crate::firmware::Platform;
+use crate::output::Output;
+use crate::probe::Host;
+use crate::{decode, vcek};
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use colorful::*;
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use structopt::StructOpt;
+
+/// `_IOWR('S', 0x0, struct sev_issue_cmd)`
+const SEV_ISSUE_CMD: u64 = 0xc018_5300;
+
+/// The command number of SNP_PLATFORM_STATUS in mainline Linux, following
+/// the SEV commands in include/uapi/linux/psp-sev.h.
+const SNP_PLATFORM_STATUS: u32 = 9;
+
+/// The number the out-of-tree SEV-SNP host patches used before the command
+/// was merged; kernels that carry them reject the mainline number.
+const SNP_PLATFORM_STATUS_LEGACY: u32 = 0x100;
+
+/// Size of an ATTESTATION_REPORT structure.
+pub const REPORT_LEN: usize = 0x4a0;
+
+/// The report signature covers everything up to the signature itself.
+const REPORT_SIGNED_LEN: usize = 0x2a0;
+
+/// The `signature_algo` of reports signed with ECDSA P-384 and SHA-384, the
+/// only algorithm the SEV-SNP firmware ABI defines.
+const SIG_ALGO_ECDSA_P384_SHA384: u32 = 1;
+
+#[derive(StructOpt)]
+pub enum SnpCmd {
+    #[structopt(about = "Verify a SEV-SNP attestation report")]
+    VerifyReport(VerifyReportArgs),
+}
+
+#[derive(StructOpt)]
+pub struct VerifyReportArgs {
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Read the VCEK (DER or PEM) from specified file"
+    )]
+    pub vcek: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Processor product name (Milan, Genoa), used to fetch the VCEK; detected if omitted"
+    )]
+    pub product: Option<vcek::Product>,
+
+    #[structopt(long, help = "Expected hex-encoded launch measurement")]
+    pub measurement: Option<String>,
+
+    #[structopt(long, help = "Expected hex-encoded host data")]
+    pub host_data: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Expected hex-encoded report data (zero-padded to 64 bytes)"
+    )]
+    pub report_data: Option<String>,
+
+    #[structopt(long, help = "Expected guest policy")]
+    pub policy: Option<String>,
+
+    #[structopt(parse(from_os_str), help = "Path of the attestation report")]
+    pub report: PathBuf,
+}
+
+#[repr(C)]
+struct IssueCmd {
+    cmd: u32,
+    data: u64,
+    error: u32,
+}
+
+/// Layout of the SNP_PLATFORM_STATUS command buffer, `struct
+/// sev_user_data_snp_status` in include/uapi/linux/psp-sev.h.
+#[repr(C)]
+#[derive(Default)]
+struct RawPlatformStatus {
+    api_major: u8,
+    api_minor: u8,
+    state: u8,
+    is_rmp_init: u8,
+    build_id: u32,
+    features: u32,
+    guest_count: u32,
+    current_tcb: u64,
+    reported_tcb: u64,
+}
+
+/// The SVNs of each firmware component making up a TCB version.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TcbVersion {
+    pub bootloader: u8,
+    pub tee: u8,
+    pub snp: u8,
+    pub microcode: u8,
+}
+
+impl From<u64> for TcbVersion {
+    fn from(raw: u64) -> Self {
+        let bytes = raw.to_le_bytes();
+
+        Self {
+            bootloader: bytes[0],
+            tee: bytes[1],
+            snp: bytes[6],
+            microcode: bytes[7],
+        }
+    }
+}
+
+impl fmt::Display for TcbVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bootloader={} tee={} snp={} microcode={}",
+            self.bootloader, self.tee, self.snp, self.microcode
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SnpState {
+    Uninitialized,
+    Initialized,
+    Unknown(u8),
+}
+
+impl fmt::Display for SnpState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnpState::Uninitialized => write!(f, "UNINIT"),
+            SnpState::Initialized => write!(f, "INIT"),
+            SnpState::Unknown(s) => write!(f, "unknown ({})", s),
+        }
+    }
+}
+
+/// SEV-SNP platform status as reported by SNP_PLATFORM_STATUS.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SnpStatus {
+    pub api_major: u8,
+    pub api_minor: u8,
+    pub build: u32,
+    pub state: SnpState,
+    pub rmp_initialized: bool,
+    pub guests: u32,
+    pub current_tcb: TcbVersion,
+    pub reported_tcb: TcbVersion,
+}
+
+impl SnpStatus {
+    pub fn initialized(&self) -> bool {
+        self.state == SnpState::Initialized
+    }
+}
+
+/// The SEV-SNP platform status, from the selected firmware backend.
+pub fn platform_status(platform: &Platform) -> Result<SnpStatus> {
+    platform
+        .firmware()?
+        .snp_platform_status()
+        .context("unable to fetch SNP platform status")
+}
+
+/// Issue SNP_PLATFORM_STATUS to the SEV firmware through /dev/sev.
+pub fn device_status() -> std::result::Result<SnpStatus, error::Error> {
+    let dev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/sev")
+        .map_err(error::Error::FirmwareIo)?;
+
+    let mut raw = RawPlatformStatus::default();
+    match issue(&dev, SNP_PLATFORM_STATUS, &mut raw) {
+        Err(error::Error::FirmwareIo(e)) if e.raw_os_error() == Some(libc::EINVAL) => {
+            issue(&dev, SNP_PLATFORM_STATUS_LEGACY, &mut raw)
+        }
+        res => res,
+    }?;
+
+    Ok(SnpStatus {
+        api_major: raw.api_major,
+        api_minor: raw.api_minor,
+        build: raw.build_id,
+        state: match raw.state {
+            0 => SnpState::Uninitialized,
+            1 => SnpState::Initialized,
+            s => SnpState::Unknown(s),
+        },
+        rmp_initialized: raw.is_rmp_init & 1 != 0,
+        guests: raw.guest_count,
+        current_tcb: raw.current_tcb.into(),
+        reported_tcb: raw.reported_tcb.into(),
+    })
+}
+
+fn issue<T>(dev: &File, cmd: u32, data: &mut T) -> std::result::Result<(), error::Error> {
+    let mut arg = IssueCmd {
+        cmd,
+        data: data as *mut T as u64,
+        error: 0,
+    };
+
+    let rc = unsafe { libc::ioctl(dev.as_raw_fd(), SEV_ISSUE_CMD as _, &mut arg) };
+    if rc < 0 {
+        return Err(match arg.error {
+            0 => error::Error::FirmwareIo(Error::last_os_error()),
+            code => error::Error::Firmware(code),
+        });
+    }
+
+    Ok(())
+}
+
+/// A firmware version as recorded in an attestation report.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub build: u8,
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.build)
+    }
+}
+
+/// A SEV-SNP ATTESTATION_REPORT, as returned to the guest by MSG_REPORT_REQ.
+#[derive(Clone, Debug, Serialize)]
+pub struct AttestationReport {
+    pub version: u32,
+    pub guest_svn: u32,
+    pub policy: u64,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub family_id: Vec<u8>,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub image_id: Vec<u8>,
+    pub vmpl: u32,
+    pub signature_algo: u32,
+    pub current_tcb: TcbVersion,
+    pub platform_info: u64,
+    pub author_key_en: bool,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub report_data: Vec<u8>,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub measurement: Vec<u8>,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub host_data: Vec<u8>,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub id_key_digest: Vec<u8>,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub author_key_digest: Vec<u8>,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub report_id: Vec<u8>,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub report_id_ma: Vec<u8>,
+    pub reported_tcb: TcbVersion,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub chip_id: Vec<u8>,
+    pub committed_tcb: TcbVersion,
+    pub current_version: FirmwareVersion,
+    pub committed_version: FirmwareVersion,
+    pub launch_tcb: TcbVersion,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub signature_r: Vec<u8>,
+    #[serde(serialize_with = "decode::hex_bytes")]
+    pub signature_s: Vec<u8>,
+    #[serde(skip)]
+    raw: Vec<u8>,
+}
+
+impl AttestationReport {
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        if raw.len() != REPORT_LEN {
+            return Err(Context::new(
+                &format!(
+                    "attestation report must be {} bytes, found {}",
+                    REPORT_LEN,
+                    raw.len()
+                ),
+                Box::<Error>::new(ErrorKind::InvalidData.into()),
+            ));
+        }
+
+        let u32_at = |o: usize| u32::from_le_bytes(raw[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(raw[o..o + 8].try_into().unwrap());
+
+        Ok(Self {
+            version: u32_at(0x00),
+            guest_svn: u32_at(0x04),
+            policy: u64_at(0x08),
+            family_id: raw[0x10..0x20].to_vec(),
+            image_id: raw[0x20..0x30].to_vec(),
+            vmpl: u32_at(0x30),
+            signature_algo: u32_at(0x34),
+            current_tcb: u64_at(0x38).into(),
+            platform_info: u64_at(0x40),
+            author_key_en: u32_at(0x48) & 1 != 0,
+            report_data: raw[0x50..0x90].to_vec(),
+            measurement: raw[0x90..0xc0].to_vec(),
+            host_data: raw[0xc0..0xe0].to_vec(),
+            id_key_digest: raw[0xe0..0x110].to_vec(),
+            author_key_digest: raw[0x110..0x140].to_vec(),
+            report_id: raw[0x140..0x160].to_vec(),
+            report_id_ma: raw[0x160..0x180].to_vec(),
+            reported_tcb: u64_at(0x180).into(),
+            chip_id: raw[0x1a0..0x1e0].to_vec(),
+            committed_tcb: u64_at(0x1e0).into(),
+            current_version: FirmwareVersion {
+                major: raw[0x1ea],
+                minor: raw[0x1e9],
+                build: raw[0x1e8],
+            },
+            committed_version: FirmwareVersion {
+                major: raw[0x1ee],
+                minor: raw[0x1ed],
+                build: raw[0x1ec],
+            },
+            launch_tcb: u64_at(0x1f0).into(),
+            signature_r: raw[0x2a0..0x2e8].to_vec(),
+            signature_s: raw[0x2e8..0x330].to_vec(),
+            raw: raw.to_vec(),
+        })
+    }
+
+    /// Check the ECDSA P-384 signature over the report with the VCEK. A
+    /// report signed with any other algorithm does not verify.
+    pub fn verify_signature(&self, vcek: &X509) -> Result<bool> {
+        if self.signature_algo != SIG_ALGO_ECDSA_P384_SHA384 {
+            return Ok(false);
+        }
+
+        // r and s are stored as little-endian integers.
+        let be = |le: &[u8]| {
+            let mut be = le.to_vec();
+            be.reverse();
+            BigNum::from_slice(&be)
+        };
+
+        let sig = be(&self.signature_r)
+            .and_then(|r| be(&self.signature_s).map(|s| (r, s)))
+            .and_then(|(r, s)| EcdsaSig::from_private_components(r, s))
+            .context("unable to decode report signature")?;
+
+        let key = vcek
+            .public_key()
+            .and_then(|k| k.ec_key())
+            .context("VCEK does not hold an EC public key")?;
+
+        let digest = openssl::sha::sha384(&self.raw[..REPORT_SIGNED_LEN]);
+        sig.verify(&digest, &key)
+            .context("unable to verify report signature")
+    }
+}
+
+pub fn cmd(
+    cmd: SnpCmd,
+    quiet: bool,
+    cache: &Cache,
+    platform: &Platform,
+    output: Output,
+) -> Result<()> {
+    match cmd {
+        SnpCmd::VerifyReport(args) => verify_report(args, quiet, cache, platform, output),
+    }
+}
+
+fn verify_report(
+    args: VerifyReportArgs,
+    quiet: bool,
+    cache: &Cache,
+    platform: &Platform,
+    output: Output,
+) -> Result<()> {
+    // Reject malformed expectations before doing any work.
+    let mut expected = vec![];
+    for (name, value) in &[
+        ("measurement", &args.measurement),
+        ("host data", &args.host_data),
+        ("report data", &args.report_data),
+    ] {
+        if let Some(value) = value {
+            let bytes = hex::decode(value.trim())
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+                .context(format!("expected {} is not hex", name))?;
+            expected.push((*name, bytes));
+        }
+    }
+    let policy = match &args.policy {
+        Some(policy) => Some(
+            parse_u64(policy)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+                .context("expected policy is not an integer")?,
+        ),
+        None => None,
+    };
+
+    let raw = std::fs::read(&args.report).context("unable to read attestation report")?;
+    let report = AttestationReport::from_bytes(&raw)?;
+
+    let vcek = match &args.vcek {
+        Some(path) => {
+            let vcek = std::fs::read(path).context("unable to read VCEK file")?;
+            vcek::load_cert(&vcek).context("unable to decode VCEK")?
+        }
+        None => {
+            let product = match args.product {
+                Some(p) => p,
+                None => vcek::product_or_detect(None, &Host(platform))?,
+            };
+            let vcek = vcek::fetch_for(
+                cache,
+                product,
+                &hex::encode(&report.chip_id),
+                &report.reported_tcb,
+            )?;
+            let vcek = vcek::load_cert(&vcek).context("unable to decode VCEK")?;
+
+            // A downloaded VCEK is only as trustworthy as the server it came
+            // from unless AMD's keys sign it.
+            let ca = vcek::ca_chain(&vcek::fetch_ca_chain(cache, product)?)?;
+            if !vcek::chained(&ca, &vcek) {
+                return Err(Context::new(
+                    &format!("the VCEK is not signed by the {} ASK/ARK chain", product),
+                    Box::new(error::Error::Verification),
+                ));
+            }
+            vcek
+        }
+    };
+
+    let mut ok = true;
+    let mut checks = vec![];
+    let mut check = |name: &str, passed: bool, detail: String| {
+        if !quiet && output == Output::Text {
+            let stat = if passed {
+                "ok".green()
+            } else {
+                "mismatch".red()
+            };
+            println!("{}: {} ({})", name, stat, detail);
+        }
+        checks.push(json!({
+            "name": name,
+            "passed": passed,
+            "detail": detail,
+        }));
+        ok &= passed;
+    };
+
+    check(
+        "signature",
+        report.verify_signature(&vcek)?,
+        match report.signature_algo {
+            SIG_ALGO_ECDSA_P384_SHA384 => "ECDSA P-384 with SHA-384".to_string(),
+            algo => format!("unsupported signature algorithm {}", algo),
+        },
+    );
+
+    let vcek_tcb = vcek::tcb(&vcek)?;
+    check(
+        "reported tcb",
+        vcek_tcb == report.reported_tcb,
+        format!("report: {}, vcek: {}", report.reported_tcb, vcek_tcb),
+    );
+
+    for (name, mut expected) in expected {
+        let actual = match name {
+            "measurement" => &report.measurement,
+            "host data" => &report.host_data,
+            _ => &report.report_data,
+        };
+        if name == "report data" && expected.len() < actual.len() {
+            expected.resize(actual.len(), 0);
+        }
+        check(name, &expected == actual, hex::encode(actual));
+    }
+
+    if let Some(expected) = policy {
+        check(
+            "policy",
+            expected == report.policy,
+            format!("{:#x}", report.policy),
+        );
+    }
+
+    output.json(&json!({
+        "valid": ok,
+        "checks": checks,
+    }))?;
+
+    if ok {
+        Ok(())
+    } else {
+        Err(Context::new(
+            "SEV-SNP attestation report verification failed",
+            Box::new(error::Error::Verification),
+        ))
+    }
+}
+
+fn parse_u64(s: &str) -> std::result::Result<u64, std::num::ParseIntError> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+
+    fn vcek() -> (EcKey<Private>, X509) {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let pkey = PKey::from_ec_key(key.clone()).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_pubkey(&pkey).unwrap();
+        builder.sign(&pkey, MessageDigest::sha384()).unwrap();
+        (key, builder.build())
+    }
+
+    /// A report with `signature_algo` set to `algo`, signed by `key`.
+    fn report(key: &EcKey<Private>, algo: u32) -> AttestationReport {
+        let mut raw = vec![0u8; REPORT_LEN];
+        raw[0x34..0x38].copy_from_slice(&algo.to_le_bytes());
+
+        let digest = openssl::sha::sha384(&raw[..REPORT_SIGNED_LEN]);
+        let sig = EcdsaSig::sign(&digest, key).unwrap();
+        for (bn, offset) in &[(sig.r(), 0x2a0), (sig.s(), 0x2e8)] {
+            let mut le = bn.to_vec();
+            le.reverse();
+            raw[*offset..*offset + le.len()].copy_from_slice(&le);
+        }
+
+        AttestationReport::from_bytes(&raw).unwrap()
+    }
+
+    #[test]
+    fn only_ecdsa_p384_signatures_verify() {
+        let (key, vcek) = vcek();
+        assert!(report(&key, SIG_ALGO_ECDSA_P384_SHA384)
+            .verify_signature(&vcek)
+            .unwrap());
+
+        // The signature itself is valid, but over a report claiming a
+        // different algorithm.
+        for algo in &[0, 2, u32::MAX] {
+            assert!(!report(&key, *algo).verify_signature(&vcek).unwrap());
+        }
+    }
+}
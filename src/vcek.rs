@@ -0,0 +1,629 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Retrieval of SEV-SNP Versioned Chip Endorsement Keys (VCEK) from the AMD
+//! Key Distribution Service.
+
+use crate::cache::{self, Cache};
+use crate::cpu::Generation;
+use crate::error::{self, Context, Contextual, Result};
+use crate::firmware::Platform;
+use crate::output::{Link, Output};
+use crate::probe::{Host, Probe};
+use crate::snp::{self, TcbVersion};
+
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use colorful::*;
+use openssl::nid::Nid;
+use openssl::x509::X509;
+use serde_json::json;
+use structopt::StructOpt;
+
+const KDS_VCEK: &str = "/vcek/v1";
+
+#[derive(StructOpt)]
+pub enum VcekCmd {
+    #[structopt(about = "Fetch this platform's VCEK certificate from the AMD KDS")]
+    Fetch {
+        #[structopt(
+            long,
+            help = "Processor product name (Milan, Genoa); detected from CPUID if omitted"
+        )]
+        product: Option<Product>,
+
+        #[structopt(parse(from_os_str), help = "VCEK certificate (DER) output file path")]
+        destination: PathBuf,
+    },
+
+    #[structopt(about = "Export the SEV-SNP ARK/ASK certificate chain from the AMD KDS")]
+    Chain {
+        #[structopt(
+            long,
+            help = "Processor product name (Milan, Genoa); detected from CPUID if omitted"
+        )]
+        product: Option<Product>,
+
+        #[structopt(parse(from_os_str), help = "ASK/ARK chain (PEM) output file path")]
+        destination: PathBuf,
+    },
+
+    #[structopt(about = "Verify the VCEK against the SEV-SNP ARK/ASK chain")]
+    Verify {
+        #[structopt(
+            long,
+            help = "Processor product name (Milan, Genoa); detected from CPUID if omitted"
+        )]
+        product: Option<Product>,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Read the ASK/ARK chain (PEM) from specified file"
+        )]
+        chain: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Read the VCEK (DER or PEM) from specified file"
+        )]
+        vcek: Option<PathBuf>,
+    },
+}
+
+/// The SEV-SNP AMD root and signing keys of a product line.
+pub struct CaChain {
+    pub ark: X509,
+    pub ask: X509,
+}
+
+/// Processor product line, as named by the KDS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Product {
+    Milan,
+    Genoa,
+}
+
+impl Product {
+    /// Deduce the product line from the generation of the probed processor.
+    pub fn detect(probe: &dyn Probe) -> Result<Self> {
+        match Generation::detect(probe)? {
+            Generation::Milan => Ok(Product::Milan),
+            Generation::Genoa | Generation::Bergamo => Ok(Product::Genoa),
+            generation => Err(Context::new(
+                &format!("no SEV-SNP product known for {} processors", generation),
+                Box::<Error>::new(ErrorKind::NotFound.into()),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Product {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Product::Milan => write!(f, "Milan"),
+            Product::Genoa => write!(f, "Genoa"),
+        }
+    }
+}
+
+impl FromStr for Product {
+    type Err = Context;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "milan" => Ok(Product::Milan),
+            "genoa" => Ok(Product::Genoa),
+            _ => Err(Context::new(
+                &format!("unknown product {}", s),
+                Box::<Error>::new(ErrorKind::InvalidInput.into()),
+            )),
+        }
+    }
+}
+
+pub fn cmd(
+    cmd: VcekCmd,
+    quiet: bool,
+    cache: &Cache,
+    platform: &Platform,
+    output: Output,
+) -> Result<()> {
+    match cmd {
+        VcekCmd::Fetch {
+            product,
+            destination,
+        } => {
+            let product = product_or_detect(product, &Host(platform))?;
+            let vcek = fetch(cache, platform, product)?;
+            fs::write(&destination, vcek)
+                .context(format!("unable to write VCEK to {}", destination.display()))?;
+
+            output.json(&json!({
+                "product": product.to_string(),
+                "destination": destination,
+            }))
+        }
+        VcekCmd::Chain {
+            product,
+            destination,
+        } => {
+            let product = product_or_detect(product, &Host(platform))?;
+            let pem = fetch_ca_chain(cache, product)?;
+            fs::write(&destination, pem).context(format!(
+                "unable to write ASK/ARK chain to {}",
+                destination.display()
+            ))?;
+
+            output.json(&json!({
+                "product": product.to_string(),
+                "destination": destination,
+            }))
+        }
+        VcekCmd::Verify {
+            product,
+            chain,
+            vcek,
+        } => verify(quiet, output, cache, platform, product, chain, vcek),
+    }
+}
+
+pub fn product_or_detect(product: Option<Product>, probe: &dyn Probe) -> Result<Product> {
+    match product {
+        Some(p) => Ok(p),
+        None => Product::detect(probe).context("failed to deduce processor product"),
+    }
+}
+
+fn verify(
+    quiet: bool,
+    output: Output,
+    cache: &Cache,
+    platform: &Platform,
+    product: Option<Product>,
+    chain: Option<PathBuf>,
+    vcek: Option<PathBuf>,
+) -> Result<()> {
+    let ca = match chain {
+        Some(path) => {
+            let pem = fs::read(&path).context("unable to read ASK/ARK chain file")?;
+            ca_chain(&pem)?
+        }
+        None => ca_chain(&fetch_ca_chain(
+            cache,
+            product_or_detect(product, &Host(platform))?,
+        )?)?,
+    };
+
+    let vcek = match vcek {
+        Some(path) => fs::read(&path).context("unable to read VCEK file")?,
+        None => fetch(
+            cache,
+            platform,
+            product_or_detect(product, &Host(platform))?,
+        )?,
+    };
+    let vcek = load_cert(&vcek).context("unable to decode VCEK")?;
+
+    let text = !quiet && output == Output::Text;
+
+    if text {
+        println!("{}", name(&vcek));
+    }
+    let links = vec![
+        status("", &ca.ask, &vcek, false, text),
+        status("   ", &ca.ark, &ca.ask, true, text),
+    ];
+    let err = links.iter().any(|l| !l.valid());
+
+    if text {
+        println!("\n • = self signed, ⬑ = signs, •̷ = invalid self sign, ⬑̸ = invalid signs");
+    }
+    output.json(&json!({
+        "valid": !err,
+        "links": links,
+    }))?;
+
+    if !err {
+        Ok(())
+    } else {
+        Err(Context::new(
+            "SEV-SNP certificate verification failed",
+            Box::new(error::Error::Verification),
+        ))
+    }
+}
+
+/// Whether the ASK of `ca` signs `vcek`, and the ARK signs the ASK and
+/// itself.
+pub fn chained(ca: &CaChain, vcek: &X509) -> bool {
+    status("", &ca.ask, vcek, false, false).valid()
+        && status("", &ca.ark, &ca.ask, true, false).valid()
+}
+
+/// Check whether `p` signs `c`, and whether a root `p` signs itself,
+/// printing the link like `verify::status` does for the legacy chain.
+fn status(pfx: &str, p: &X509, c: &X509, root: bool, print: bool) -> Link {
+    let signs = |p: &X509, c: &X509| {
+        p.public_key()
+            .and_then(|key| c.verify(&key))
+            .unwrap_or(false)
+    };
+
+    let sig_valid = signs(p, c);
+    let lnk = if sig_valid {
+        "⬑".green()
+    } else {
+        "⬑̸".red()
+    };
+
+    let mut link = Link {
+        signer: name(p),
+        subject: name(c),
+        signature_valid: sig_valid,
+        self_signature_valid: None,
+    };
+
+    if root {
+        let selfsig_valid = signs(p, p);
+        let slf = if selfsig_valid {
+            "•".green()
+        } else {
+            "•̷".red()
+        };
+        if print {
+            println!("{}{}{} {}", pfx, slf, lnk, name(p));
+        }
+        link.self_signature_valid = Some(selfsig_valid);
+    } else if print {
+        println!("{} {} {}", pfx, lnk, name(p));
+    }
+
+    link
+}
+
+fn name(cert: &X509) -> String {
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .map(|cn| String::from_utf8_lossy(cn.data().as_slice()).to_string())
+        .unwrap_or_else(|| "unnamed certificate".to_string())
+}
+
+/// Decode a certificate in either DER or PEM form.
+pub fn load_cert(bytes: &[u8]) -> Result<X509> {
+    X509::from_der(bytes)
+        .or_else(|_| X509::from_pem(bytes))
+        .context("certificate is neither DER nor PEM encoded")
+}
+
+/// Split a KDS `cert_chain` PEM bundle into its ARK and ASK.
+pub fn ca_chain(pem: &[u8]) -> Result<CaChain> {
+    let certs = X509::stack_from_pem(pem).context("unable to decode ASK/ARK chain")?;
+
+    // The ARK is the self-issued certificate of the pair.
+    let (ark, ask): (Vec<X509>, Vec<X509>) = certs.into_iter().partition(|c| {
+        c.issuer_name()
+            .entries()
+            .map(|e| e.data().as_slice())
+            .eq(c.subject_name().entries().map(|e| e.data().as_slice()))
+    });
+
+    match (ark.into_iter().next(), ask.into_iter().next()) {
+        (Some(ark), Some(ask)) => Ok(CaChain { ark, ask }),
+        _ => Err(Context::new(
+            "ASK/ARK chain must contain an ARK and an ASK",
+            Box::<Error>::new(ErrorKind::InvalidData.into()),
+        )),
+    }
+}
+
+/// Download the PEM-encoded ASK/ARK chain of a product line.
+pub fn fetch_ca_chain(cache: &Cache, product: Product) -> Result<Vec<u8>> {
+    let url = format!("{}/{}/cert_chain", KDS_VCEK, product);
+
+    cache
+        .fetch(&cache::ca_chain_key(product), &url, |pem| {
+            ca_chain(pem).map(|_| pem.to_vec())
+        })
+        .context("unable to fetch ASK/ARK chain")
+}
+
+/// Download the DER-encoded VCEK matching this chip and its reported TCB.
+pub fn fetch(cache: &Cache, platform: &Platform, product: Product) -> Result<Vec<u8>> {
+    let id = crate::chip_id(platform)?;
+    let tcb = snp::platform_status(platform)?.reported_tcb;
+
+    fetch_for(cache, product, &id.to_lowercase(), &tcb)
+}
+
+/// Download the DER-encoded VCEK of the given chip (hex-encoded ID) at a TCB.
+pub fn fetch_for(cache: &Cache, product: Product, hwid: &str, tcb: &TcbVersion) -> Result<Vec<u8>> {
+    let url = vcek_url(product, hwid, tcb);
+
+    cache
+        .fetch(&cache::vcek_key(product, hwid, tcb)?, &url, |der| {
+            X509::from_der(der)
+                .context("VCEK is not DER encoded")
+                .map(|_| der.to_vec())
+        })
+        .context("unable to fetch VCEK")
+}
+
+fn vcek_url(product: Product, hwid: &str, tcb: &TcbVersion) -> String {
+    format!(
+        "{}/{}/{}?blSPL={:02}&teeSPL={:02}&snpSPL={:02}&ucodeSPL={:02}",
+        KDS_VCEK, product, hwid, tcb.bootloader, tcb.tee, tcb.snp, tcb.microcode
+    )
+}
+
+/// Read the TCB version a VCEK was issued for from its AMD-specific
+/// extensions (1.3.6.1.4.1.3704.1.3.{1,2,3,8}).
+pub fn tcb(vcek: &X509) -> Result<TcbVersion> {
+    let der = vcek.to_der().context("unable to encode VCEK")?;
+    let extensions = extensions(&der).ok_or_else(|| {
+        Context::new(
+            "VCEK extensions are malformed",
+            Box::<Error>::new(ErrorKind::InvalidData.into()),
+        )
+    })?;
+
+    let spl = |arc: u8, name: &str| {
+        let oid = [0x2b, 0x06, 0x01, 0x04, 0x01, 0x9c, 0x78, 0x01, 0x03, arc];
+        extensions
+            .iter()
+            .find(|(id, _)| *id == &oid[..])
+            .and_then(|(_, value)| small_integer(value))
+            .ok_or_else(|| {
+                Context::new(
+                    &format!("VCEK has no valid {} extension", name),
+                    Box::<Error>::new(ErrorKind::InvalidData.into()),
+                )
+            })
+    };
+
+    Ok(TcbVersion {
+        bootloader: spl(1, "blSPL")?,
+        tee: spl(2, "teeSPL")?,
+        snp: spl(3, "snpSPL")?,
+        microcode: spl(8, "ucodeSPL")?,
+    })
+}
+
+const DER_BOOLEAN: u8 = 0x01;
+const DER_INTEGER: u8 = 0x02;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_OID: u8 = 0x06;
+const DER_SEQUENCE: u8 = 0x30;
+/// The `[3] EXPLICIT` tag of the extensions of a TBSCertificate.
+const DER_EXTENSIONS: u8 = 0xa3;
+
+/// Split the DER encoding at the start of `der` into its tag, its contents
+/// and whatever follows it.
+fn tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+
+    // Lengths above 127 are given in the following 1 to 4 bytes.
+    let (len, rest) = match len {
+        0..=0x7f => (len as usize, rest),
+        0x81..=0x84 => {
+            let n = (len & 0x7f) as usize;
+            let len = rest
+                .get(..n)?
+                .iter()
+                .fold(0, |len, b| len << 8 | *b as usize);
+            (len, &rest[n..])
+        }
+        _ => return None,
+    };
+
+    Some((tag, rest.get(..len)?, &rest[len..]))
+}
+
+/// The contents of the DER value of type `tag` at the start of `der`, and
+/// whatever follows it.
+fn expect(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match tlv(der)? {
+        (t, contents, rest) if t == tag => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// The `(extnID, extnValue)` contents of every extension of a DER-encoded
+/// X.509 certificate.
+fn extensions(cert: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    let (cert, _) = expect(cert, DER_SEQUENCE)?;
+    let (mut tbs, _) = expect(cert, DER_SEQUENCE)?;
+
+    // The extensions are the last, optional field of the TBSCertificate.
+    let mut list = loop {
+        if tbs.is_empty() {
+            return Some(vec![]);
+        }
+        let (tag, contents, rest) = tlv(tbs)?;
+        if tag == DER_EXTENSIONS {
+            break expect(contents, DER_SEQUENCE)?.0;
+        }
+        tbs = rest;
+    };
+
+    let mut extensions = vec![];
+    while !list.is_empty() {
+        let (extension, rest) = expect(list, DER_SEQUENCE)?;
+        list = rest;
+
+        let (oid, mut extension) = expect(extension, DER_OID)?;
+        if let Some((_, rest)) = expect(extension, DER_BOOLEAN) {
+            extension = rest;
+        }
+        let (value, _) = expect(extension, DER_OCTET_STRING)?;
+
+        extensions.push((oid, value));
+    }
+
+    Some(extensions)
+}
+
+/// Decode a DER INTEGER that fits in a byte, such as an SPL.
+fn small_integer(der: &[u8]) -> Option<u8> {
+    match expect(der, DER_INTEGER)? {
+        // Values above 0x7f carry a leading zero byte.
+        ([v], []) if *v < 0x80 => Some(*v),
+        ([0, v], []) if *v >= 0x80 => Some(*v),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EXIT_INVALID_INPUT;
+    use crate::probe::Profile;
+
+    use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509Extension, X509Name};
+
+    /// A certificate carrying `extensions` (OID and DER value), in addition
+    /// to a critical basicConstraints extension.
+    fn cert(extensions: &[(&str, Vec<u8>)]) -> X509 {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "SEV-VCEK")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().build().unwrap())
+            .unwrap();
+        for (oid, value) in extensions {
+            let ext = X509Extension::new_from_der(
+                &Asn1Object::from_str(oid).unwrap(),
+                false,
+                &Asn1OctetString::new_from_bytes(value).unwrap(),
+            )
+            .unwrap();
+            builder.append_extension(ext).unwrap();
+        }
+        builder.sign(&key, MessageDigest::sha384()).unwrap();
+
+        builder.build()
+    }
+
+    /// The AMD extensions of a Milan VCEK, encoded as the KDS encodes them,
+    /// at TCB bootloader=3 tee=0 snp=8 microcode=209.
+    fn amd_extensions() -> Vec<(&'static str, Vec<u8>)> {
+        let mut hwid = vec![0x04, 0x40];
+        hwid.extend_from_slice(&[0x5e; 64]);
+
+        vec![
+            ("1.3.6.1.4.1.3704.1.1", vec![0x02, 0x01, 0x00]),
+            ("1.3.6.1.4.1.3704.1.2", b"\x16\x08Milan-B0".to_vec()),
+            ("1.3.6.1.4.1.3704.1.3.1", vec![0x02, 0x01, 0x03]),
+            ("1.3.6.1.4.1.3704.1.3.2", vec![0x02, 0x01, 0x00]),
+            ("1.3.6.1.4.1.3704.1.3.3", vec![0x02, 0x01, 0x08]),
+            ("1.3.6.1.4.1.3704.1.3.4", vec![0x02, 0x01, 0x00]),
+            ("1.3.6.1.4.1.3704.1.3.5", vec![0x02, 0x01, 0x00]),
+            ("1.3.6.1.4.1.3704.1.3.6", vec![0x02, 0x01, 0x00]),
+            ("1.3.6.1.4.1.3704.1.3.7", vec![0x02, 0x01, 0x00]),
+            ("1.3.6.1.4.1.3704.1.3.8", vec![0x02, 0x02, 0x00, 0xd1]),
+            ("1.3.6.1.4.1.3704.1.4", hwid),
+        ]
+    }
+
+    #[test]
+    fn tcb_is_read_from_extensions() {
+        let vcek = cert(&amd_extensions());
+
+        assert_eq!(
+            tcb(&vcek).unwrap(),
+            TcbVersion {
+                bootloader: 3,
+                tee: 0,
+                snp: 8,
+                microcode: 209,
+            }
+        );
+    }
+
+    #[test]
+    fn tcb_extensions_must_be_small_integers() {
+        let invalid = [
+            // Missing.
+            None,
+            // Not an INTEGER.
+            Some(vec![0x04, 0x01, 0xd1]),
+            // Negative, too large, and not minimally encoded.
+            Some(vec![0x02, 0x01, 0xd1]),
+            Some(vec![0x02, 0x02, 0x01, 0x00]),
+            Some(vec![0x02, 0x02, 0x00, 0x73]),
+            // Followed by garbage.
+            Some(vec![0x02, 0x01, 0x73, 0x00]),
+        ];
+
+        for value in invalid.iter() {
+            let mut extensions = amd_extensions();
+            extensions.retain(|(oid, _)| *oid != "1.3.6.1.4.1.3704.1.3.8");
+            if let Some(value) = value {
+                extensions.push(("1.3.6.1.4.1.3704.1.3.8", value.clone()));
+            }
+
+            let err = tcb(&cert(&extensions)).unwrap_err();
+            assert_eq!(err.exit_code(), EXIT_INVALID_INPUT, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn long_lengths_are_decoded() {
+        assert_eq!(
+            tlv(&[0x04, 0x81, 0x01, 0xaa, 0xbb]),
+            Some((0x04, &[0xaa][..], &[0xbb][..]))
+        );
+        assert_eq!(
+            tlv(&[0x04, 0x82, 0x00, 0x01, 0xaa]),
+            Some((0x04, &[0xaa][..], &[][..]))
+        );
+        assert_eq!(tlv(&[0x04, 0x81, 0x02, 0xaa]), None);
+        assert_eq!(tlv(&[0x04, 0x80, 0xaa]), None);
+    }
+
+    #[test]
+    fn product_follows_the_processor_generation() {
+        let profile = |eax: u32| Profile::with_cpu(b"AuthenticAMD", eax);
+
+        assert_eq!(
+            Product::detect(&profile(0x00a0_0f11)).unwrap(),
+            Product::Milan
+        );
+        assert_eq!(
+            Product::detect(&profile(0x00a1_0f11)).unwrap(),
+            Product::Genoa
+        );
+        assert_eq!(
+            Product::detect(&profile(0x00aa_0f01)).unwrap(),
+            Product::Genoa
+        );
+        assert!(Product::detect(&profile(0x0083_0f10)).is_err());
+    }
+}
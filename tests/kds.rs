@@ -0,0 +1,590 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! End-to-end tests of the commands that download from the KDS, run against
+//! a local mock server.
+
+mod mock_kds;
+
+use std::fs;
+
+use mock_kds::{MockKds, MockProxy, Reply};
+use serde_json::Value;
+
+const CHAIN: &str = "/vcek/v1/Milan/cert_chain";
+
+fn json(stdout: &[u8]) -> Value {
+    serde_json::from_slice(stdout).expect("stdout is not JSON")
+}
+
+#[test]
+fn chain_is_downloaded_and_cached() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Body(chain.clone())]);
+
+    let dest = kds.dir().join("chain.pem");
+    let dest = dest.to_str().unwrap();
+    let out = kds.sevctl(&["vcek", "chain", "--product", "milan", dest]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(fs::read(dest).unwrap(), chain);
+
+    let out = kds.sevctl(&["--offline", "vcek", "chain", "--product", "milan", dest]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(kds.hits(CHAIN), 1);
+}
+
+#[test]
+fn corrupt_cache_entries_are_downloaded_again() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Body(chain.clone())]);
+
+    let cached = kds.dir().join("cache/snp/Milan/cert_chain.pem");
+    fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    fs::write(&cached, "not a certificate chain").unwrap();
+
+    let dest = kds.dir().join("chain.pem");
+    let dest = dest.to_str().unwrap();
+    let out = kds.sevctl(&["vcek", "chain", "--product", "milan", dest]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(fs::read(dest).unwrap(), chain);
+    assert_eq!(fs::read(&cached).unwrap(), chain);
+    assert_eq!(kds.hits(CHAIN), 1);
+}
+
+#[test]
+fn invalid_downloads_are_not_cached() {
+    let kds = MockKds::start();
+    kds.route(
+        CHAIN,
+        vec![Reply::Body(b"<html>maintenance</html>".to_vec())],
+    );
+
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl(&[
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ]);
+    assert!(!out.status.success(), "{:?}", out);
+    assert!(!dest.exists());
+    assert!(!kds.dir().join("cache/snp/Milan/cert_chain.pem").exists());
+}
+
+#[test]
+fn vcek_verifies_against_downloaded_chain() {
+    let kds = MockKds::start();
+    let (chain, vcek) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Chunked(chain)]);
+
+    let file = kds.dir().join("vcek.der");
+    fs::write(&file, vcek).unwrap();
+
+    let out = kds.sevctl(&[
+        "--output",
+        "json",
+        "vcek",
+        "verify",
+        "--product",
+        "milan",
+        "--vcek",
+        file.to_str().unwrap(),
+    ]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(json(&out.stdout)["valid"], true);
+}
+
+#[test]
+fn vcek_from_another_chain_fails_verification() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    let (_, vcek) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Body(chain)]);
+
+    let file = kds.dir().join("vcek.der");
+    fs::write(&file, vcek).unwrap();
+
+    let args = ["vcek", "verify", "--product", "milan", "--vcek"];
+    let out = kds.sevctl(&[&args[..], &[file.to_str().unwrap()]].concat());
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn redirects_are_followed() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Redirect("/mirror/cert_chain".into())]);
+    kds.route("/mirror/cert_chain", vec![Reply::Body(chain.clone())]);
+
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl(&[
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(fs::read(dest).unwrap(), chain);
+}
+
+#[test]
+fn redirects_to_plain_http_are_refused() {
+    let kds = MockKds::start();
+    let insecure = kds.url().replace("https://", "http://") + "/mirror/cert_chain";
+    kds.route(CHAIN, vec![Reply::Redirect(insecure)]);
+
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl(&[
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(4));
+    assert_eq!(kds.hits(CHAIN), 1);
+    assert_eq!(kds.requests(), 1);
+    assert!(!dest.exists());
+    assert!(!kds.dir().join("cache/snp/Milan/cert_chain.pem").exists());
+}
+
+#[test]
+fn unavailable_server_is_retried() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::status(503), Reply::Body(chain.clone())]);
+
+    let dest = kds.dir().join("chain.pem");
+    let dest = dest.to_str().unwrap();
+    let out = kds.sevctl(&["vcek", "chain", "--product", "milan", dest]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(fs::read(dest).unwrap(), chain);
+    assert_eq!(kds.hits(CHAIN), 2);
+}
+
+#[test]
+fn retries_give_up_after_the_last_attempt() {
+    let kds = MockKds::start();
+    kds.route(CHAIN, vec![Reply::status(503)]);
+
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl(&[
+        "--retries",
+        "1",
+        "--max-backoff",
+        "0",
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(5));
+    assert_eq!(kds.hits(CHAIN), 2);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("previous attempts"));
+}
+
+#[test]
+fn rate_limiting_is_retried() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(
+        CHAIN,
+        vec![
+            Reply::retry_after(429, "1"),
+            Reply::status(503),
+            Reply::Body(chain),
+        ],
+    );
+
+    let dest = kds.dir().join("chain.pem");
+    let dest = dest.to_str().unwrap();
+    let out = kds.sevctl(&[
+        "--max-backoff",
+        "1",
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest,
+    ]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(kds.hits(CHAIN), 3);
+}
+
+#[test]
+fn offline_cache_miss_is_a_network_failure() {
+    let kds = MockKds::start();
+
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl(&[
+        "--offline",
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("network access is disabled"));
+    assert_eq!(kds.hits(CHAIN), 0);
+    assert!(!dest.exists());
+}
+
+#[test]
+fn malformed_settings_only_fail_downloads() {
+    let kds = MockKds::start();
+    let vars = [("SEVCTL_RETRIES", "many")];
+    fs::write(kds.dir().join("config"), "retry-jitter = never\n").unwrap();
+
+    let out = kds.sevctl_env(&["policy", "decode", "0x1"], &vars);
+    assert!(out.status.success(), "{:?}", out);
+
+    let dest = kds.dir().join("chain.pem");
+    let args = [
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ];
+    let out = kds.sevctl_env(&args, &vars);
+    assert_eq!(out.status.code(), Some(7));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("must be a number"));
+    assert_eq!(kds.hits(CHAIN), 0);
+}
+
+#[test]
+fn missing_certificate_fails_fast() {
+    let kds = MockKds::start();
+    kds.route(CHAIN, vec![Reply::status(404)]);
+
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl(&[
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(5));
+    assert_eq!(kds.hits(CHAIN), 1);
+    assert!(!dest.exists());
+}
+
+#[test]
+fn truncated_bodies_are_retried_then_fail() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Truncated(chain)]);
+
+    let dest = kds.dir().join("chain.pem");
+    let dest = dest.to_str().unwrap();
+    let out = kds.sevctl(&[
+        "--retries",
+        "2",
+        "--max-backoff",
+        "1",
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest,
+    ]);
+    assert_eq!(out.status.code(), Some(4));
+    assert_eq!(kds.hits(CHAIN), 3);
+}
+
+#[test]
+fn untrusted_server_is_rejected() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Body(chain)]);
+
+    // Trust a CA that didn't issue the server's certificate instead.
+    let (_, other) = mock_kds::cert("Other CA", None, true);
+    fs::write(kds.dir().join("ca.pem"), other.to_pem().unwrap()).unwrap();
+
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl(&[
+        "vcek",
+        "chain",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(4));
+    assert_eq!(kds.hits(CHAIN), 0);
+}
+
+#[test]
+fn downloads_are_tunneled_through_the_proxy() {
+    let kds = MockKds::start();
+    let proxy = MockProxy::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Body(chain.clone())]);
+
+    let url = proxy.url().replace("http://", "http://kds%40user:p%3Ass@");
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl_env(
+        &[
+            "vcek",
+            "chain",
+            "--product",
+            "milan",
+            dest.to_str().unwrap(),
+        ],
+        &[("HTTPS_PROXY", &url)],
+    );
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(fs::read(dest).unwrap(), chain);
+    assert_eq!(kds.hits(CHAIN), 1);
+
+    let port = kds.url().rsplit(':').next().unwrap().to_string();
+    let requests = proxy.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].starts_with(&format!("CONNECT localhost:{} HTTP/1.1\r\n", port)));
+    assert!(requests[0].contains(&format!(
+        "Proxy-Authorization: Basic {}\r\n",
+        base64::encode("kds@user:p:ss")
+    )));
+}
+
+#[test]
+fn no_proxy_hosts_are_contacted_directly() {
+    let kds = MockKds::start();
+    let proxy = MockProxy::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    kds.route(CHAIN, vec![Reply::Body(chain)]);
+
+    let dest = kds.dir().join("chain.pem");
+    let out = kds.sevctl_env(
+        &[
+            "vcek",
+            "chain",
+            "--product",
+            "milan",
+            dest.to_str().unwrap(),
+        ],
+        &[
+            ("https_proxy", &proxy.url()),
+            ("no_proxy", "example.com,localhost"),
+        ],
+    );
+    assert!(out.status.success(), "{:?}", out);
+    assert!(proxy.requests().is_empty());
+}
+
+#[test]
+fn generation_is_shown_without_downloading() {
+    let kds = MockKds::start();
+
+    // Whatever the processor running the test, the CEK is not fetched.
+    let out = kds.sevctl(&["--firmware", "emulated", "show", "generation"]);
+    assert_eq!(kds.requests(), 0, "{:?}", out);
+}
+
+#[test]
+fn export_downloads_and_caches_the_cek() {
+    let kds = MockKds::start();
+    let cek = mock_kds::sev_cek();
+    let target = mock_kds::cek_target(&mock_kds::emulated_id());
+    kds.route(&target, vec![Reply::Body(cek.clone())]);
+
+    let dest = kds.dir().join("chain.cert");
+    let dest = dest.to_str().unwrap();
+    let out = kds.sevctl(&["--firmware", "emulated", "export", dest]);
+    assert!(out.status.success(), "{:?}", out);
+    let chain = fs::read(dest).unwrap();
+    assert_eq!(chain.len(), 4 * cek.len());
+    assert_eq!(&chain[3 * cek.len()..], &cek[..]);
+
+    let cached = kds
+        .dir()
+        .join(format!("cache/cek/{}.cert", mock_kds::emulated_id()));
+    assert_eq!(fs::read(cached).unwrap(), cek);
+
+    fs::remove_file(dest).unwrap();
+    let out = kds.sevctl(&["--offline", "--firmware", "emulated", "export", dest]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(&fs::read(dest).unwrap()[3 * cek.len()..], &cek[..]);
+    assert_eq!(kds.hits(&target), 1);
+}
+
+#[test]
+fn export_offline_without_a_cached_cek_fails() {
+    let kds = MockKds::start();
+
+    let dest = kds.dir().join("chain.cert");
+    let out = kds.sevctl(&[
+        "--offline",
+        "--firmware",
+        "emulated",
+        "export",
+        dest.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(4));
+    assert_eq!(kds.requests(), 0);
+    assert!(!dest.exists());
+}
+
+#[test]
+fn verify_checks_the_downloaded_cek() {
+    let kds = MockKds::start();
+    let target = mock_kds::cek_target(&mock_kds::emulated_id());
+    kds.route(&target, vec![Reply::Body(mock_kds::sev_cek())]);
+
+    // The mock CEK is not signed by an AMD ASK, so no built-in CA chain
+    // matches it, whether it was just downloaded or found in the cache.
+    for args in &[&["verify"][..], &["--offline", "verify"][..]] {
+        let out = kds.sevctl(&[&["--firmware", "emulated"][..], args].concat());
+        assert_eq!(out.status.code(), Some(2), "{:?}", out);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("failed to deduce platform generation"),
+            "{}",
+            stderr
+        );
+    }
+    assert_eq!(kds.hits(&target), 1);
+}
+
+#[test]
+fn verify_reports_a_missing_cek() {
+    let kds = MockKds::start();
+    let target = mock_kds::cek_target(&mock_kds::emulated_id());
+    kds.route(&target, vec![Reply::status(404)]);
+
+    let out = kds.sevctl(&["--firmware", "emulated", "verify"]);
+    assert_eq!(out.status.code(), Some(5));
+    assert_eq!(kds.hits(&target), 1);
+}
+
+#[test]
+fn vcek_is_fetched_for_the_reported_tcb() {
+    let kds = MockKds::start();
+    let (_, vcek) = mock_kds::snp_chain("Milan");
+    let target = mock_kds::emulated_vcek_target("Milan");
+    kds.route(&target, vec![Reply::Body(vcek.clone())]);
+
+    let dest = kds.dir().join("vcek.der");
+    let dest = dest.to_str().unwrap();
+    let args = [
+        "--firmware",
+        "emulated",
+        "vcek",
+        "fetch",
+        "--product",
+        "milan",
+        dest,
+    ];
+    let out = kds.sevctl(&args);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(fs::read(dest).unwrap(), vcek);
+
+    fs::remove_file(dest).unwrap();
+    let out = kds.sevctl(&[&["--offline"][..], &args].concat());
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(fs::read(dest).unwrap(), vcek);
+    assert_eq!(kds.hits(&target), 1);
+}
+
+#[test]
+fn vcek_fetch_offline_without_a_cached_vcek_fails() {
+    let kds = MockKds::start();
+
+    let dest = kds.dir().join("vcek.der");
+    let out = kds.sevctl(&[
+        "--offline",
+        "--firmware",
+        "emulated",
+        "vcek",
+        "fetch",
+        "--product",
+        "milan",
+        dest.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(4));
+    assert_eq!(kds.requests(), 0);
+    assert!(!dest.exists());
+}
+
+#[test]
+fn report_expectations_must_be_well_formed() {
+    let kds = MockKds::start();
+
+    // Expectations are checked before the report or VCEK are read.
+    for arg in &[
+        "--measurement=zz",
+        "--host-data=0",
+        "--report-data=not hex",
+        "--policy=0xzz",
+    ] {
+        let out = kds.sevctl(&[
+            "snp",
+            "verify-report",
+            "--vcek",
+            "missing.der",
+            arg,
+            "missing.bin",
+        ]);
+        assert_eq!(out.status.code(), Some(7), "{}: {:?}", arg, out);
+    }
+    assert_eq!(kds.requests(), 0);
+}
+
+#[test]
+fn fetched_vcek_must_chain_to_the_ark() {
+    let kds = MockKds::start();
+    let (chain, _) = mock_kds::snp_chain("Milan");
+    let (_, vcek) = mock_kds::snp_chain("Milan");
+    let target = format!(
+        "/vcek/v1/Milan/{}?blSPL=00&teeSPL=00&snpSPL=00&ucodeSPL=00",
+        "00".repeat(64)
+    );
+    kds.route(CHAIN, vec![Reply::Body(chain)]);
+    kds.route(&target, vec![Reply::Body(vcek)]);
+
+    let report = kds.dir().join("report.bin");
+    fs::write(&report, vec![0u8; 0x4a0]).unwrap();
+
+    let out = kds.sevctl(&[
+        "snp",
+        "verify-report",
+        "--product",
+        "milan",
+        report.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(2), "{:?}", out);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("ASK/ARK"), "{}", stderr);
+    assert_eq!(kds.hits(&target), 1);
+    assert_eq!(kds.hits(CHAIN), 1);
+}
+
+#[test]
+fn imported_ceks_need_a_chip_id() {
+    let kds = MockKds::start();
+    let file = kds.dir().join("cek.cert");
+    fs::write(&file, mock_kds::sev_cek()).unwrap();
+    let file = file.to_str().unwrap();
+
+    let bad = [
+        "../../../outside".to_string(),
+        "5E".repeat(63),
+        "zz".repeat(64),
+    ];
+    for id in &bad {
+        let out = kds.sevctl(&["cache", "import", "cek", "--id", id, file]);
+        assert_eq!(out.status.code(), Some(7), "{}: {:?}", id, out);
+    }
+    assert!(!kds.dir().join("cache").exists());
+
+    let id = mock_kds::emulated_id();
+    let out = kds.sevctl(&["cache", "import", "cek", "--id", &id, file]);
+    assert!(out.status.success(), "{:?}", out);
+    assert!(kds.dir().join(format!("cache/cek/{}.cert", id)).exists());
+}
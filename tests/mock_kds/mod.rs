@@ -0,0 +1,425 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A local stand-in for the AMD Key Distribution Service: an HTTPS server
+//! trusted through a throwaway CA that serves canned responses, and helpers
+//! to run sevctl against it.
+
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use native_tls::{Identity, TlsAcceptor};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509Name, X509};
+
+/// A canned response.
+#[derive(Clone, Debug)]
+pub enum Reply {
+    /// 200 with this body.
+    Body(Vec<u8>),
+    /// 200 with this body, sent with chunked transfer encoding.
+    Chunked(Vec<u8>),
+    /// 200 announcing this body, of which only the first half is sent.
+    Truncated(Vec<u8>),
+    /// A redirect to this path.
+    Redirect(String),
+    /// This status, with these headers and no body.
+    Status(u16, Vec<(String, String)>),
+}
+
+impl Reply {
+    pub fn status(status: u16) -> Self {
+        Reply::Status(status, vec![])
+    }
+
+    pub fn retry_after(status: u16, value: &str) -> Self {
+        Reply::Status(status, vec![("Retry-After".into(), value.into())])
+    }
+}
+
+#[derive(Default)]
+struct Routes {
+    /// Replies by request target; the last reply of a route is repeated.
+    replies: HashMap<String, VecDeque<Reply>>,
+    /// Request targets in the order they were received.
+    requests: Vec<String>,
+}
+
+pub struct MockKds {
+    port: u16,
+    dir: PathBuf,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl MockKds {
+    /// Start serving on an ephemeral port of localhost.
+    pub fn start() -> Self {
+        let dir = temp_dir();
+
+        let (ca_key, ca) = cert("Mock KDS CA", None, true);
+        let (key, cert) = cert("localhost", Some((&ca_key, &ca)), false);
+        fs::write(dir.join("ca.pem"), ca.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("config"), "").unwrap();
+
+        let identity = Identity::from_pkcs8(
+            &cert.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let acceptor = Arc::new(TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let routes = Arc::new(Mutex::new(Routes::default()));
+
+        let shared = routes.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let acceptor = acceptor.clone();
+                let routes = shared.clone();
+                thread::spawn(move || serve(stream, &acceptor, &routes));
+            }
+        });
+
+        Self { port, dir, routes }
+    }
+
+    pub fn url(&self) -> String {
+        format!("https://localhost:{}", self.port)
+    }
+
+    /// A scratch directory that is removed with the server.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Answer requests for `target` (path and query) with `replies` in
+    /// turn, repeating the last one.
+    pub fn route(&self, target: &str, replies: Vec<Reply>) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.replies.insert(target.to_string(), replies.into());
+    }
+
+    /// How many requests were received in all.
+    pub fn requests(&self) -> usize {
+        self.routes.lock().unwrap().requests.len()
+    }
+
+    /// How many requests for `target` were received.
+    pub fn hits(&self, target: &str) -> usize {
+        let routes = self.routes.lock().unwrap();
+        routes.requests.iter().filter(|r| *r == target).count()
+    }
+
+    /// Run sevctl against this server, trusting the CA in `dir()/ca.pem`,
+    /// with its own cache, no proxy, no configuration file and no retry
+    /// jitter.
+    pub fn sevctl(&self, args: &[&str]) -> Output {
+        self.sevctl_env(args, &[])
+    }
+
+    /// `sevctl`, with these environment variables set.
+    pub fn sevctl_env(&self, args: &[&str], vars: &[(&str, &str)]) -> Output {
+        let cache = self.dir.join("cache");
+        let ca = self.dir.join("ca.pem");
+        let config = self.dir.join("config");
+
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_sevctl"));
+        for (var, _) in env::vars() {
+            if var.starts_with("SEVCTL_") || var.to_ascii_lowercase().ends_with("_proxy") {
+                cmd.env_remove(var);
+            }
+        }
+
+        cmd.arg("--kds-url")
+            .arg(self.url())
+            .arg("--ca-file")
+            .arg(ca)
+            .arg("--cache-dir")
+            .arg(cache)
+            .arg("--config")
+            .arg(config)
+            .args(&["--retry-jitter", "0"])
+            .args(args)
+            .envs(vars.iter().cloned())
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for MockKds {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// An HTTP proxy that tunnels CONNECT requests to their target and records
+/// each request head.
+pub struct MockProxy {
+    port: u16,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockProxy {
+    /// Start proxying on an ephemeral port of localhost.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let shared = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let requests = shared.clone();
+                thread::spawn(move || tunnel(stream, &requests));
+            }
+        });
+
+        Self { port, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// The heads of the requests received, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn tunnel(mut client: TcpStream, requests: &Mutex<Vec<String>>) {
+    let head = match read_head(&mut client) {
+        Some(head) => head,
+        None => return,
+    };
+    requests.lock().unwrap().push(head.clone());
+
+    let mut line = head.split_whitespace();
+    let server = match (line.next(), line.next()) {
+        (Some("CONNECT"), Some(target)) => TcpStream::connect(target).ok(),
+        _ => None,
+    };
+    let mut server = match server {
+        Some(server) => server,
+        None => {
+            let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n");
+            return;
+        }
+    };
+    if client
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .is_err()
+    {
+        return;
+    }
+
+    let (mut client_rx, mut server_tx) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+    let upstream = thread::spawn(move || {
+        let _ = std::io::copy(&mut client_rx, &mut server_tx);
+        let _ = server_tx.shutdown(std::net::Shutdown::Write);
+    });
+    let _ = std::io::copy(&mut server, &mut client);
+    let _ = client.shutdown(std::net::Shutdown::Write);
+    let _ = upstream.join();
+}
+
+/// Read a request head a byte at a time, so nothing after it is consumed.
+fn read_head(stream: &mut dyn Read) -> Option<String> {
+    let mut head = vec![];
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut byte) {
+            Ok(1) => head.push(byte[0]),
+            _ => return None,
+        }
+    }
+
+    Some(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn serve(stream: TcpStream, acceptor: &TlsAcceptor, routes: &Mutex<Routes>) {
+    let mut stream = match acceptor.accept(stream) {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+
+    let head = match read_head(&mut stream) {
+        Some(head) => head,
+        None => return,
+    };
+    let target = match head.split_whitespace().nth(1) {
+        Some(target) => target.to_string(),
+        None => return,
+    };
+
+    let reply = {
+        let mut routes = routes.lock().unwrap();
+        routes.requests.push(target.clone());
+        match routes.replies.get_mut(&target) {
+            Some(replies) if replies.len() > 1 => replies.pop_front(),
+            Some(replies) => replies.front().cloned(),
+            None => None,
+        }
+    };
+
+    let response = match reply.unwrap_or_else(|| Reply::status(404)) {
+        Reply::Body(body) => response(200, &[], &body, body.len()),
+        Reply::Chunked(body) => {
+            let mut chunked = vec![];
+            for chunk in body.chunks(100) {
+                chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                chunked.extend_from_slice(chunk);
+                chunked.extend_from_slice(b"\r\n");
+            }
+            chunked.extend_from_slice(b"0\r\n\r\n");
+
+            let mut rsp = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            rsp.extend_from_slice(&chunked);
+            rsp
+        }
+        Reply::Truncated(body) => response(200, &[], &body[..body.len() / 2], body.len()),
+        Reply::Redirect(location) => {
+            let headers = [("Location".to_string(), location)];
+            response(302, &headers, &[], 0)
+        }
+        Reply::Status(status, headers) => response(status, &headers, &[], 0),
+    };
+
+    let _ = stream.write_all(&response);
+    let _ = stream.flush();
+    let _ = stream.shutdown();
+}
+
+fn response(status: u16, headers: &[(String, String)], body: &[u8], len: usize) -> Vec<u8> {
+    let mut rsp = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\n", status, len);
+    for (name, value) in headers {
+        rsp.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    rsp.push_str("\r\n");
+
+    let mut rsp = rsp.into_bytes();
+    rsp.extend_from_slice(body);
+    rsp
+}
+
+/// A fresh, empty directory under the system temporary directory.
+fn temp_dir() -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = env::temp_dir().join(format!(
+        "sevctl-test-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+static SERIAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Create a P-256 certificate for `name`, signed by `issuer` or by itself.
+/// Leaf certificates name their subject in a DNS subject alternative name.
+pub fn cert(
+    name: &str,
+    issuer: Option<(&PKey<Private>, &X509)>,
+    ca: bool,
+) -> (PKey<Private>, X509) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+    let mut subject = X509Name::builder().unwrap();
+    subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+    let subject = subject.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(SERIAL.fetch_add(1, Ordering::SeqCst) as u32 + 1).unwrap();
+    builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder
+        .set_issuer_name(issuer.map_or(&subject, |(_, cert)| cert.subject_name()))
+        .unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+
+    if ca {
+        let constraints = BasicConstraints::new().critical().ca().build().unwrap();
+        builder.append_extension(constraints).unwrap();
+    } else {
+        let san = SubjectAlternativeName::new()
+            .dns(name)
+            .build(&builder.x509v3_context(issuer.map(|(_, cert)| &**cert), None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+    }
+
+    let signer = issuer.map_or(&key, |(key, _)| key);
+    builder.sign(signer, MessageDigest::sha256()).unwrap();
+
+    (key, builder.build())
+}
+
+/// The chip identifier of `--firmware emulated`, in hex.
+pub fn emulated_id() -> String {
+    "5E".repeat(64)
+}
+
+/// The KDS path of the CEK of chip `id`.
+pub fn cek_target(id: &str) -> String {
+    format!("/cek/id/{}", id)
+}
+
+/// The KDS path of the VCEK of the emulated chip at the TCB version it
+/// reports.
+pub fn emulated_vcek_target(product: &str) -> String {
+    format!(
+        "/vcek/v1/{}/{}?blSPL=03&teeSPL=00&snpSPL=08&ucodeSPL=115",
+        product,
+        emulated_id().to_lowercase()
+    )
+}
+
+/// A SEV CEK certificate, as the KDS serves it.
+pub fn sev_cek() -> Vec<u8> {
+    let (cek, _) = sev::certs::sev::Certificate::generate(sev::certs::sev::Usage::CEK).unwrap();
+
+    let mut bytes = vec![];
+    codicon::Encoder::encode(&cek, &mut bytes, ()).unwrap();
+    bytes
+}
+
+/// A KDS `cert_chain` bundle (ASK, then ARK) and a VCEK signed by the ASK.
+pub fn snp_chain(product: &str) -> (Vec<u8>, Vec<u8>) {
+    let (ark_key, ark) = cert(&format!("ARK-{}", product), None, true);
+    let (ask_key, ask) = cert(&format!("SEV-{}", product), Some((&ark_key, &ark)), true);
+    let (_, vcek) = cert("SEV-VCEK", Some((&ask_key, &ask)), false);
+
+    let mut chain = ask.to_pem().unwrap();
+    chain.extend_from_slice(&ark.to_pem().unwrap());
+
+    (chain, vcek.to_der().unwrap())
+}
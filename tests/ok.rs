@@ -0,0 +1,351 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tests of `sevctl ok` run against recorded host profiles.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use serde_json::Value;
+
+const MILAN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/profiles/milan.json");
+
+/// A scratch directory for one test, removed when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("sevctl-ok-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    /// Write `profile` to a file in this directory.
+    fn profile(&self, profile: &Value) -> PathBuf {
+        let path = self.0.join("profile.json");
+        fs::write(&path, profile.to_string()).unwrap();
+        path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn milan() -> Value {
+    serde_json::from_slice(&fs::read(MILAN).unwrap()).unwrap()
+}
+
+fn ok(profile: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_sevctl"))
+        .args(&["ok", "--format", "json", "--profile"])
+        .arg(profile)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// The report of the test called `name`, wherever it is in the tree.
+fn find<'a>(tests: &'a Value, name: &str) -> Option<&'a Value> {
+    tests.as_array()?.iter().find_map(|t| {
+        if t["name"] == name {
+            Some(t)
+        } else {
+            find(&t["children"], name)
+        }
+    })
+}
+
+fn state(out: &Output, name: &str) -> String {
+    let report: Value = serde_json::from_slice(&out.stdout).expect("stdout is not JSON");
+    let test = find(&report["tests"], name).expect("no such test");
+    test["state"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn supported_host_passes() {
+    let out = ok(Path::new(MILAN), &[]);
+    assert!(out.status.success(), "{:?}", out);
+
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(report["passed"], true);
+    assert_eq!(state(&out, "Secure Nested Paging (SEV-SNP)"), "pass");
+
+    let vmpls = find(&report["tests"], "Number of VMPLs").unwrap();
+    assert_eq!(vmpls["message"], "4");
+}
+
+#[test]
+fn malformed_network_settings_are_ignored() {
+    let out = Command::new(env!("CARGO_BIN_EXE_sevctl"))
+        .env("SEVCTL_MAX_BACKOFF", "forever")
+        .args(&["ok", "--format", "json", "--profile", MILAN])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{:?}", out);
+}
+
+#[test]
+fn sev_disabled_in_kvm_fails() {
+    let scratch = Scratch::new("kvm");
+    let mut profile = milan();
+    profile["files"]["/sys/module/kvm_amd/parameters/sev"] = serde_json::json!({ "Ok": "N\n" });
+
+    let out = ok(&scratch.profile(&profile), &["sev"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(state(&out, "SEV enabled in KVM"), "fail");
+    assert_eq!(state(&out, "Reading /dev/sev"), "pass");
+}
+
+#[test]
+fn recorded_errors_are_replayed() {
+    let scratch = Scratch::new("errors");
+    let mut profile = milan();
+    profile["devices"]["/dev/sev"]["write"] = serde_json::json!({
+        "Err": { "errno": 13, "message": "Permission denied (os error 13)" }
+    });
+    profile["files"]
+        .as_object_mut()
+        .unwrap()
+        .remove("/sys/module/kvm_amd/parameters/sev");
+
+    let out = ok(&scratch.profile(&profile), &["sev"]);
+    assert_eq!(out.status.code(), Some(2));
+
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    let write = find(&report["tests"], "Writing /dev/sev").unwrap();
+    assert_eq!(
+        write["message"],
+        "/dev/sev not writable: Permission denied (os error 13)"
+    );
+
+    // Probes missing from the profile fail as if the file didn't exist.
+    let kvm = find(&report["tests"], "SEV enabled in KVM").unwrap();
+    assert_eq!(
+        kvm["message"],
+        "Error - /sys/module/kvm_amd/parameters/sev does not exist"
+    );
+}
+
+#[test]
+fn generation_limits_the_tests_run() {
+    let scratch = Scratch::new("generation");
+    let mut profile = milan();
+    // Clear the SEV-SNP bit.
+    profile["cpuid"]["0x8000001f"]["eax"] = Value::from(0x1006f);
+    let profile = scratch.profile(&profile);
+
+    let out = ok(&profile, &["es"]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(state(&out, "Secure Nested Paging (SEV-SNP)"), "skip");
+
+    let out = ok(&profile, &["snp"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(state(&out, "Secure Nested Paging (SEV-SNP)"), "fail");
+}
+
+#[test]
+fn recording_a_profile_reproduces_it() {
+    let scratch = Scratch::new("record");
+    let recorded = scratch.0.join("recorded.json");
+
+    // Record while only running the SEV tests: the profile must still hold
+    // everything probed by the other generations.
+    let out = ok(
+        Path::new(MILAN),
+        &["--record", recorded.to_str().unwrap(), "sev"],
+    );
+    assert!(out.status.success(), "{:?}", out);
+
+    let profile: Value = serde_json::from_slice(&fs::read(&recorded).unwrap()).unwrap();
+    assert_eq!(profile, milan());
+
+    let replayed = ok(&recorded, &[]);
+    assert_eq!(replayed.stdout, ok(Path::new(MILAN), &[]).stdout);
+}
+
+#[test]
+fn rules_fail_hosts_short_of_thresholds() {
+    let scratch = Scratch::new("rules-fail");
+    let rules = scratch.0.join("rules");
+    fs::write(
+        &rules,
+        "min-memlock-soft = unlimited\n\
+         min-encrypted-guests = 600\n\
+         min-firmware = 1.55.22\n\
+         min-snp-tcb = bootloader=3 snp=9\n",
+    )
+    .unwrap();
+
+    let out = ok(Path::new(MILAN), &["--rules", rules.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(2));
+
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    let expected = [
+        (
+            "Memlock resource limit",
+            "Soft: 8388608 | Hard: 8388608 (required: soft at least unlimited)",
+        ),
+        (
+            "Number of encrypted guests supported simultaneously",
+            "509 (required: at least 600)",
+        ),
+        ("SEV firmware version", "1.55.21 (required: at least 1.55.22)"),
+        (
+            "SEV-SNP TCB version",
+            "bootloader=3 tee=0 snp=8 microcode=115 (required: at least bootloader=3 tee=0 snp=9 microcode=0)",
+        ),
+    ];
+    for (name, message) in &expected {
+        let test = find(&report["tests"], name).unwrap();
+        assert_eq!(test["state"], "fail", "{}", name);
+        assert_eq!(test["message"], *message);
+    }
+}
+
+#[test]
+fn rules_pass_hosts_meeting_thresholds() {
+    let scratch = Scratch::new("rules-pass");
+    let rules = scratch.0.join("rules");
+    fs::write(
+        &rules,
+        "# The recorded host exactly.\n\
+         min-memlock-soft = 8388608\n\
+         min-memlock-hard = 8388608\n\
+         min-encrypted-guests = 509\n\
+         min-firmware = 1.55.21\n\
+         min-snp-tcb = bootloader=3,tee=0,snp=8,microcode=115\n",
+    )
+    .unwrap();
+
+    let out = ok(Path::new(MILAN), &["--rules", rules.to_str().unwrap()]);
+    assert!(out.status.success(), "{:?}", out);
+}
+
+#[test]
+fn invalid_rules_are_rejected() {
+    let scratch = Scratch::new("rules-invalid");
+    let rules = scratch.0.join("rules");
+    fs::write(&rules, "min-firmware = 1.55\n").unwrap();
+
+    let out = ok(Path::new(MILAN), &["--rules", rules.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(7));
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn snp_readiness_failures_are_explained() {
+    let scratch = Scratch::new("snp");
+    let mut profile = milan();
+    profile["msrs"]["0xc0010010"] = serde_json::json!({ "Ok": 0x840000 });
+    profile["dirs"]["/sys/class/iommu"] = serde_json::json!({ "Ok": [] });
+    profile["files"]["/sys/module/kvm_amd/parameters/sev_snp"] = serde_json::json!({ "Ok": "N\n" });
+    profile["snp_status"]["Ok"]["state"] = "Uninitialized".into();
+
+    let out = ok(&scratch.profile(&profile), &["snp"]);
+    assert_eq!(out.status.code(), Some(2));
+
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    let failed = [
+        ("SEV-SNP enabled in SYSCFG MSR", "BIOS"),
+        ("IOMMU enabled", "amd_iommu=off"),
+        ("SEV-SNP enabled in KVM", "sev_snp=1"),
+        ("SEV-SNP firmware initialized", "UNINIT"),
+    ];
+    for (name, hint) in &failed {
+        let test = find(&report["tests"], name).unwrap();
+        assert_eq!(test["state"], "fail", "{}", name);
+        assert!(test["message"].as_str().unwrap().contains(hint), "{}", test);
+    }
+    assert_eq!(state(&out, "RMP table reserved"), "skip");
+    assert_eq!(state(&out, "SEV-SNP supported by KVM"), "pass");
+
+    // The RMP table must be reserved, and addresses are only visible to root.
+    let mut profile = milan();
+    let iomem = "00000000-00000000 : Reserved\n00000000-00000000 : System RAM\n";
+    profile["files"]["/proc/iomem"] = serde_json::json!({ "Ok": iomem });
+    let out = ok(&scratch.profile(&profile), &["snp"]);
+    assert_eq!(out.status.code(), Some(2));
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    let rmp = find(&report["tests"], "RMP table reserved").unwrap();
+    assert_eq!(
+        rmp["message"],
+        "Error - /proc/iomem hides addresses; run as root"
+    );
+
+    let iomem = "00000000-3fffffff : System RAM\n";
+    profile["files"]["/proc/iomem"] = serde_json::json!({ "Ok": iomem });
+    let out = ok(&scratch.profile(&profile), &["snp"]);
+    assert_eq!(state(&out, "RMP table reserved"), "fail");
+}
+
+#[test]
+fn es_readiness_failures_are_explained() {
+    let scratch = Scratch::new("es");
+    let mut profile = milan();
+    // All ASIDs are available to SEV guests only.
+    profile["cpuid"]["0x8000001f"]["edx"] = 1.into();
+    profile["files"]
+        .as_object_mut()
+        .unwrap()
+        .remove("/sys/module/kvm_amd/parameters/sev_es");
+    profile["sev_status"] = serde_json::json!({
+        "Ok": { "version": { "major": 0, "minor": 16, "build": 3 }, "encrypted_state": false }
+    });
+
+    let out = ok(&scratch.profile(&profile), &["es"]);
+    assert_eq!(out.status.code(), Some(2));
+
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    let failed = [
+        ("SEV-ES enabled in KVM", "lacks SEV-ES support"),
+        ("ASIDs for SEV-ES guests", "(SEV: 509)"),
+        ("SEV firmware API version for SEV-ES", "at least 0.17"),
+        ("SEV-ES supported by firmware", "ENCRYPTED_STATE"),
+    ];
+    for (name, hint) in &failed {
+        let test = find(&report["tests"], name).unwrap();
+        assert_eq!(test["state"], "fail", "{}", name);
+        assert!(test["message"].as_str().unwrap().contains(hint), "{}", test);
+    }
+
+    let out = ok(Path::new(MILAN), &["es"]);
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    let asids = find(&report["tests"], "ASIDs for SEV-ES guests").unwrap();
+    assert_eq!(asids["message"], "SEV-ES: 99 | SEV: 410");
+}
+
+#[test]
+fn generation_is_detected_from_cpuid() {
+    let scratch = Scratch::new("detect");
+    let mut profile = milan();
+    // Family 0x17, model 0x31 is Rome, whatever the brand string says.
+    profile["cpuid"]["0x00000001"]["eax"] = 0x0083_0f10.into();
+    let out = ok(&scratch.profile(&profile), &[]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(state(&out, "ASIDs for SEV-ES guests"), "pass");
+    assert_eq!(state(&out, "Secure Nested Paging (SEV-SNP)"), "skip");
+}
+
+#[test]
+fn unlimited_memlock_meets_any_rule() {
+    let scratch = Scratch::new("unlimited");
+    let mut profile = milan();
+    profile["memlock"]["Ok"] = serde_json::json!({ "soft": u64::MAX, "hard": u64::MAX });
+    let rules = scratch.0.join("rules");
+    fs::write(&rules, "min-memlock-soft = unlimited\n").unwrap();
+
+    let out = ok(
+        &scratch.profile(&profile),
+        &["--rules", rules.to_str().unwrap()],
+    );
+    assert!(out.status.success(), "{:?}", out);
+
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    let memlock = find(&report["tests"], "Memlock resource limit").unwrap();
+    assert_eq!(memlock["message"], "Soft: unlimited | Hard: unlimited");
+}